@@ -0,0 +1,220 @@
+// What a bot does, one command per tick. Either a script played in a loop, or random activities
+// picked one after another
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use shared::commands::ClientCommand;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Activity {
+    Walk,
+    Fly,
+    // Spawns a prop and stands around for a bit
+    Props,
+    // Tries to pick up whatever is in front of it
+    Pickup,
+}
+
+// Input held for a number of ticks. Jump, pickup and prop spawn only happen on the first one,
+// same as a key press
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Step {
+    pub ticks: u32,
+    pub movement: [i8; 2],
+    // Radians
+    pub yaw: f64,
+    pub fly: bool,
+    pub run: bool,
+    pub sit: bool,
+    pub jump: bool,
+    pub pickup: bool,
+    pub prop_spawn: Option<u8>,
+}
+
+impl Default for Step {
+    fn default() -> Self {
+        Self {
+            ticks: 1,
+            movement: [0, 0],
+            yaw: 0.0,
+            fly: false,
+            run: false,
+            sit: false,
+            jump: false,
+            pickup: false,
+            prop_spawn: None,
+        }
+    }
+}
+
+enum Source {
+    Script {
+        steps: Vec<Step>,
+        next: usize,
+    },
+    Random {
+        // Boxed, it's much bigger than a script
+        rng: Box<StdRng>,
+        mix: Vec<Activity>,
+        prop_count: u8,
+    },
+}
+
+pub struct Behavior {
+    source: Source,
+    step: Step,
+    // Ticks the current step has been running for
+    elapsed: u32,
+}
+
+impl Behavior {
+    // Script has to last at least one tick, see Config::load_script
+    pub fn script(steps: Vec<Step>) -> Self {
+        Self::new(Source::Script { steps, next: 0 })
+    }
+    pub fn random(seed: u64, mix: Vec<Activity>, prop_count: u8) -> Self {
+        Self::new(Source::Random {
+            rng: Box::new(StdRng::seed_from_u64(seed)),
+            mix,
+            prop_count,
+        })
+    }
+    fn new(source: Source) -> Self {
+        Self {
+            source,
+            // Finished right away, so the first call picks a real one
+            step: Step {
+                ticks: 0,
+                ..Default::default()
+            },
+            elapsed: 0,
+        }
+    }
+    // Input for the next tick
+    pub fn next(&mut self, sequence: u32) -> ClientCommand {
+        while self.elapsed >= self.step.ticks {
+            self.step = self.next_step();
+            self.elapsed = 0;
+        }
+        let step = &self.step;
+        let first = self.elapsed == 0;
+        self.elapsed += 1;
+        ClientCommand {
+            sequence,
+            // Bot knows which ticks it got, see Bot::update
+            tick_ack: 0,
+            movement_direction: na::Vector2::new(step.movement[0], step.movement[1]),
+            orientation: na::UnitQuaternion::from_euler_angles(0.0, 0.0, step.yaw),
+            fly: step.fly,
+            run: step.run,
+            sit: step.sit,
+            jump: step.jump && first,
+            pickup: step.pickup && first,
+            prop_spawn: step.prop_spawn.filter(|_| first),
+        }
+    }
+    fn next_step(&mut self) -> Step {
+        match &mut self.source {
+            Source::Script { steps, next } => {
+                let step = steps[*next].clone();
+                *next = (*next + 1) % steps.len();
+                step
+            }
+            Source::Random {
+                rng,
+                mix,
+                prop_count,
+            } => {
+                let activity = mix[rng.gen_range(0, mix.len())];
+                random_step(rng, activity, *prop_count)
+            }
+        }
+    }
+}
+
+fn random_step(rng: &mut StdRng, activity: Activity, prop_count: u8) -> Step {
+    let yaw = rng.gen_range(0.0, std::f64::consts::PI * 2.0);
+    // Full speed in one of 8 directions
+    let mut movement = [0, 0];
+    while movement == [0, 0] {
+        movement = [rng.gen_range(-1, 2) * 127, rng.gen_range(-1, 2) * 127];
+    }
+    match activity {
+        Activity::Walk => Step {
+            ticks: rng.gen_range(60, 300),
+            movement,
+            yaw,
+            run: rng.gen(),
+            jump: rng.gen_bool(0.25),
+            ..Default::default()
+        },
+        Activity::Fly => Step {
+            ticks: rng.gen_range(60, 300),
+            movement,
+            yaw,
+            fly: true,
+            ..Default::default()
+        },
+        Activity::Props => Step {
+            ticks: rng.gen_range(30, 120),
+            yaw,
+            prop_spawn: Some(rng.gen_range(0, prop_count.max(1))),
+            ..Default::default()
+        },
+        Activity::Pickup => Step {
+            ticks: rng.gen_range(30, 120),
+            yaw,
+            pickup: true,
+            ..Default::default()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn script_loops_and_actions_fire_once() {
+        let steps = vec![
+            Step {
+                ticks: 2,
+                prop_spawn: Some(1),
+                ..Default::default()
+            },
+            Step {
+                ticks: 0,
+                ..Default::default()
+            },
+            Step {
+                ticks: 1,
+                movement: [127, 0],
+                ..Default::default()
+            },
+        ];
+        let mut behavior = Behavior::script(steps);
+        let commands: Vec<_> = (0..6).map(|sequence| behavior.next(sequence)).collect();
+        let spawns: Vec<_> = commands.iter().map(|command| command.prop_spawn).collect();
+        assert_eq!(spawns, vec![Some(1), None, None, Some(1), None, None]);
+        assert_eq!(commands[2].movement_direction, na::Vector2::new(127, 0));
+        assert_eq!(commands[5].movement_direction, na::Vector2::new(127, 0));
+        assert_eq!(commands[4].sequence, 4);
+    }
+
+    #[test]
+    fn random_bots_are_reproducible() {
+        let mix = vec![Activity::Walk, Activity::Fly, Activity::Props];
+        let mut first = Behavior::random(7, mix.clone(), 2);
+        let mut second = Behavior::random(7, mix, 2);
+        for sequence in 0..1000 {
+            let (a, b) = (first.next(sequence), second.next(sequence));
+            assert_eq!(a.movement_direction, b.movement_direction);
+            assert_eq!(a.fly, b.fly);
+            assert_eq!(a.prop_spawn, b.prop_spawn);
+            assert!(a.prop_spawn.map_or(true, |prop| prop < 2));
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use crate::behavior::Behavior;
+use planet_gen::base::network::{self, ServerCommand};
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+// Commands that never get acked are forgotten after that many
+const MAX_IN_FLIGHT: usize = 1024;
+
+pub enum State {
+    Connecting,
+    Playing { tickrate: u8 },
+    Gone(String),
+}
+
+// What happened since the last report
+#[derive(Default)]
+pub struct Report {
+    // Send to ack, so it includes up to a tick server waited before applying the command
+    pub rtt: Vec<Duration>,
+    // Distinct server ticks anything arrived for
+    pub ticks: usize,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+pub struct Bot {
+    pub name: String,
+    pub state: State,
+    client: network::Client,
+    behavior: Behavior,
+    sequence: u32,
+    // When each command not acked yet was sent, oldest first
+    in_flight: VecDeque<(u32, Instant)>,
+    newest_tick: u64,
+    report: Report,
+    reported_in: u64,
+    reported_out: u64,
+}
+
+impl Bot {
+    pub fn connect(
+        mut config: planet_gen::base::config::Config,
+        name: String,
+        behavior: Behavior,
+    ) -> Self {
+        config.player_name = Some(name.clone());
+        Self {
+            name,
+            state: State::Connecting,
+            client: network::spawn(config),
+            behavior,
+            sequence: 0,
+            in_flight: VecDeque::new(),
+            newest_tick: 0,
+            report: Report::default(),
+            reported_in: 0,
+            reported_out: 0,
+        }
+    }
+    // Called once a server tick
+    pub fn update(&mut self) {
+        while let Ok(command) = self.client.network_receiver.try_recv() {
+            self.on_server_command(command);
+        }
+        if let State::Playing { .. } = self.state {
+            self.sequence = self.sequence.wrapping_add(1);
+            let mut command = self.behavior.next(self.sequence);
+            command.tick_ack = self.newest_tick;
+            if self.client.network_sender.send(command).is_ok() {
+                if self.in_flight.len() >= MAX_IN_FLIGHT {
+                    self.in_flight.pop_front();
+                }
+                self.in_flight.push_back((self.sequence, Instant::now()));
+            }
+        }
+    }
+    pub fn take_report(&mut self) -> Report {
+        let traffic = &self.client.traffic;
+        let bytes_in = traffic.bytes_in.load(Ordering::Relaxed);
+        let bytes_out = traffic.bytes_out.load(Ordering::Relaxed);
+        let mut report = std::mem::take(&mut self.report);
+        report.bytes_in = bytes_in - self.reported_in;
+        report.bytes_out = bytes_out - self.reported_out;
+        self.reported_in = bytes_in;
+        self.reported_out = bytes_out;
+        report
+    }
+    fn on_server_command(&mut self, command: ServerCommand) {
+        match command {
+            ServerCommand::ServerInfoUpdate(info) => {
+                self.state = State::Playing {
+                    tickrate: info.tickrate,
+                };
+            }
+            ServerCommand::Tick(tick) => self.observe(tick.tick),
+            ServerCommand::Positions(update) => {
+                self.observe(update.tick);
+                self.acknowledge(update.input_ack);
+            }
+            ServerCommand::Snapshot(_) | ServerCommand::Layers(_) => {}
+            ServerCommand::Rejected(rejection) => self.state = State::Gone(rejection.reason),
+            // Commands sent before that are never acked, server gets a fresh connection
+            ServerCommand::Reconnecting(_) => {
+                self.state = State::Connecting;
+                self.in_flight.clear();
+                self.newest_tick = 0;
+            }
+            ServerCommand::ConnectFailed(reason) | ServerCommand::Disconnected(reason) => {
+                self.state = State::Gone(reason)
+            }
+        }
+    }
+    fn observe(&mut self, tick: u64) {
+        // Datagrams may come out of order, those are not counted
+        if tick > self.newest_tick {
+            self.newest_tick = tick;
+            self.report.ticks += 1;
+        }
+    }
+    fn acknowledge(&mut self, ack: u32) {
+        let now = Instant::now();
+        while let Some(&(sequence, sent)) = self.in_flight.front() {
+            if sequence > ack {
+                break;
+            }
+            self.in_flight.pop_front();
+            if sequence == ack {
+                self.report.rtt.push(now - sent);
+            }
+        }
+    }
+}
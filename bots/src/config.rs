@@ -0,0 +1,150 @@
+use crate::behavior::{Activity, Step};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+const DEFAULT_PATH: &str = "./bots.json";
+
+const USAGE: &str = "Options:
+    --config <path>            Config file, ./bots.json by default
+    --server <address:port>    Server to connect to
+    --server-name <name>       Name server certificate is issued for
+    --pin <fingerprint>        Only accept server with this certificate fingerprint, may be repeated
+    --bots <count>             How many bots to connect
+    --ramp-up <ms>             Delay between connecting two bots
+    --duration <secs>          Disconnect everyone after that long, runs until killed otherwise
+    --report-interval <secs>   How often stats are printed
+    --mix <activities>         Comma separated: walk, fly, props, pickup
+    --script <path>            Replay steps from this file instead of random activities
+    --seed <seed>              Bots are numbered, each one uses seed + its number";
+
+// Everything is optional in the file, missing fields are taken from Default
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // Address, certificate verification and so on, same as for the game client. Player name is ignored
+    pub client: planet_gen::base::config::Config,
+    pub bots: usize,
+    // In milliseconds
+    pub ramp_up: u64,
+    // In seconds
+    pub duration: Option<u64>,
+    pub report_interval: u64,
+    // What random bots do, see behavior::Activity
+    pub mix: Vec<Activity>,
+    // JSON array of behavior::Step, replayed in a loop
+    pub script: Option<String>,
+    pub seed: u64,
+    // Bots spawn props with ids below that
+    pub prop_count: u8,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            client: planet_gen::base::config::Config {
+                server_address: "127.0.0.1:1234".to_string(),
+                ..Default::default()
+            },
+            bots: 10,
+            ramp_up: 100,
+            duration: None,
+            report_interval: 5,
+            mix: vec![
+                Activity::Walk,
+                Activity::Fly,
+                Activity::Props,
+                Activity::Pickup,
+            ],
+            script: None,
+            seed: 0,
+            prop_count: 2,
+        }
+    }
+}
+
+impl Config {
+    // Reads config file and applies command line overrides on top of it
+    pub fn from_args() -> Result<Self, Box<dyn Error>> {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        let mut overrides = vec![];
+        let mut path = None;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let value = match args.next() {
+                Some(value) => value.clone(),
+                None => return Err(format!("Missing value for {}\n{}", arg, USAGE).into()),
+            };
+            if arg == "--config" {
+                path = Some(value);
+            } else {
+                overrides.push((arg.clone(), value));
+            }
+        }
+        let mut config = match path {
+            Some(path) => Self::load(std::path::Path::new(&path))?,
+            None => {
+                let path = std::path::Path::new(DEFAULT_PATH);
+                if path.exists() {
+                    Self::load(path)?
+                } else {
+                    Self::default()
+                }
+            }
+        };
+        for (arg, value) in overrides {
+            config
+                .set(&arg, &value)
+                .map_err(|e| format!("Invalid value for {}: {}", arg, e))?;
+        }
+        if config.report_interval == 0 {
+            return Err("Report interval can't be zero".into());
+        }
+        if config.mix.is_empty() && config.script.is_none() {
+            return Err("Bots have nothing to do, mix is empty".into());
+        }
+        Ok(config)
+    }
+    pub fn load(path: &std::path::Path) -> Result<Self, Box<dyn Error>> {
+        let file = std::fs::File::open(path)
+            .map_err(|e| format!("Can't open config {}: {}", path.display(), e))?;
+        let reader = std::io::BufReader::new(file);
+        let config = serde_json::from_reader(reader)
+            .map_err(|e| format!("Can't parse config {}: {}", path.display(), e))?;
+        Ok(config)
+    }
+    pub fn load_script(&self) -> Result<Option<Vec<Step>>, Box<dyn Error>> {
+        let path = match &self.script {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let file =
+            std::fs::File::open(path).map_err(|e| format!("Can't open script {}: {}", path, e))?;
+        let steps: Vec<Step> = serde_json::from_reader(std::io::BufReader::new(file))
+            .map_err(|e| format!("Can't parse script {}: {}", path, e))?;
+        if steps.iter().all(|step| step.ticks == 0) {
+            return Err(format!("Script {} doesn't last a single tick", path).into());
+        }
+        Ok(Some(steps))
+    }
+    fn set(&mut self, arg: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        match arg {
+            "--server" => self.client.server_address = value.to_string(),
+            "--server-name" => self.client.server_name = value.to_string(),
+            "--pin" => self.client.pinned_fingerprints.push(value.to_string()),
+            "--bots" => self.bots = value.parse()?,
+            "--ramp-up" => self.ramp_up = value.parse()?,
+            "--duration" => self.duration = Some(value.parse()?),
+            "--report-interval" => self.report_interval = value.parse()?,
+            "--mix" => {
+                self.mix = value
+                    .split(',')
+                    .map(|activity| serde_json::from_value(activity.trim().into()))
+                    .collect::<Result<_, _>>()?
+            }
+            "--script" => self.script = Some(value.to_string()),
+            "--seed" => self.seed = value.parse()?,
+            _ => return Err(format!("Unknown option\n{}", USAGE).into()),
+        }
+        Ok(())
+    }
+}
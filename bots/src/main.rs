@@ -0,0 +1,159 @@
+// Headless clients for load testing. Connects a number of bots to a server, each one sends a command
+// every tick like a real player would. Prints what every bot gets back, so it's visible when
+// the server stops keeping up: ticks per second drop and round trip times grow
+//
+// NOTE: Every bot gets its own network thread, same as the game client. Fine for a few hundred
+
+extern crate nalgebra as na;
+
+mod behavior;
+mod bot;
+mod config;
+
+use behavior::Behavior;
+use bot::{Bot, Report, State};
+use std::time::{Duration, Instant};
+
+fn main() {
+    let config = match config::Config::from_args() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("[BOTS] {}", e);
+            std::process::exit(1);
+        }
+    };
+    let script = match config.load_script() {
+        Ok(script) => script,
+        Err(e) => {
+            println!("[BOTS] {}", e);
+            std::process::exit(1);
+        }
+    };
+    println!(
+        "[BOTS] Connecting {} bots to {}",
+        config.bots, config.client.server_address
+    );
+
+    let start = Instant::now();
+    let ramp_up = Duration::from_millis(config.ramp_up);
+    let report_interval = Duration::from_secs(config.report_interval);
+    let duration = config.duration.map(Duration::from_secs);
+    let mut bots: Vec<Bot> = Vec::with_capacity(config.bots);
+    let mut last_report = start;
+    loop {
+        let now = Instant::now();
+        // Connect the next one once it's due
+        if bots.len() < config.bots && now - start >= ramp_up * bots.len() as u32 {
+            let number = bots.len();
+            let behavior = match &script {
+                Some(steps) => Behavior::script(steps.clone()),
+                None => Behavior::random(
+                    config.seed + number as u64,
+                    config.mix.clone(),
+                    config.prop_count,
+                ),
+            };
+            bots.push(Bot::connect(
+                config.client.clone(),
+                format!("bot_{}", number),
+                behavior,
+            ));
+        }
+
+        let mut tickrate = None;
+        for bot in &mut bots {
+            let was_gone = matches!(bot.state, State::Gone(_));
+            let was_playing = matches!(bot.state, State::Playing { .. });
+            bot.update();
+            match &bot.state {
+                State::Gone(reason) if !was_gone => {
+                    println!("[BOTS] {} disconnected: {}", bot.name, reason);
+                }
+                State::Connecting if was_playing => {
+                    println!("[BOTS] {} lost connection, reconnecting", bot.name);
+                }
+                State::Playing { tickrate: rate } => tickrate = Some(*rate),
+                _ => {}
+            }
+        }
+
+        if now - last_report >= report_interval {
+            report(&mut bots, now - last_report);
+            last_report = now;
+        }
+        let everyone_gone =
+            bots.len() == config.bots && bots.iter().all(|bot| matches!(bot.state, State::Gone(_)));
+        if everyone_gone || duration.map_or(false, |duration| now - start >= duration) {
+            report(&mut bots, now - last_report);
+            break;
+        }
+
+        // Bots send a command every tick, so they run at server tickrate. 60 until anyone knows better
+        let tick = Duration::from_secs(1) / tickrate.unwrap_or(60) as u32;
+        if let Some(left) = tick.checked_sub(now.elapsed()) {
+            std::thread::sleep(left);
+        }
+    }
+    // Dropping the command channel finishes the stream, so server sees everyone leave right away.
+    // Network threads need a moment for that
+    drop(bots);
+    std::thread::sleep(Duration::from_millis(500));
+}
+
+fn report(bots: &mut [Bot], elapsed: Duration) {
+    let seconds = elapsed.as_secs_f64().max(1.0e-3);
+    let mut connected = 0;
+    let mut all_rtt = vec![];
+    let mut min_tickrate: Option<f64> = None;
+    let mut total_in = 0;
+    for bot in bots.iter_mut() {
+        let report = bot.take_report();
+        total_in += report.bytes_in;
+        if !matches!(bot.state, State::Playing { .. }) {
+            continue;
+        }
+        connected += 1;
+        let tickrate = report.ticks as f64 / seconds;
+        min_tickrate = Some(min_tickrate.map_or(tickrate, |min| min.min(tickrate)));
+        println!(
+            "[BOTS] {}: rtt {}, {:.1} ticks/s, {:.1} KiB/s in, {:.1} KiB/s out",
+            bot.name,
+            rtt_summary(&report),
+            tickrate,
+            report.bytes_in as f64 / 1024.0 / seconds,
+            report.bytes_out as f64 / 1024.0 / seconds,
+        );
+        all_rtt.extend(report.rtt);
+    }
+    all_rtt.sort();
+    let percentile = |p: f64| {
+        all_rtt
+            .get(((all_rtt.len() as f64 * p) as usize).min(all_rtt.len().saturating_sub(1)))
+            .map_or("-".to_string(), |rtt| format_ms(*rtt))
+    };
+    println!(
+        "[BOTS] {}/{} playing, rtt p50 {} p99 {}, slowest {:.1} ticks/s, {:.1} KiB/s in total",
+        connected,
+        bots.len(),
+        percentile(0.5),
+        percentile(0.99),
+        min_tickrate.unwrap_or(0.0),
+        total_in as f64 / 1024.0 / seconds,
+    );
+}
+
+fn rtt_summary(report: &Report) -> String {
+    if report.rtt.is_empty() {
+        return "-".to_string();
+    }
+    let total: Duration = report.rtt.iter().sum();
+    format!(
+        "avg {} max {}",
+        format_ms(total / report.rtt.len() as u32),
+        format_ms(*report.rtt.iter().max().unwrap())
+    )
+}
+
+fn format_ms(duration: Duration) -> String {
+    format!("{:.1}ms", duration.as_secs_f64() * 1000.0)
+}
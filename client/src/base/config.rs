@@ -0,0 +1,138 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+const DEFAULT_PATH: &str = "./client.json";
+
+const USAGE: &str = "Options:
+    --config <path>            Config file, ./client.json by default
+    --server <address:port>    Server to connect to
+    --server-name <name>       Name server certificate is issued for
+    --name <name>              Player name
+    --connect-timeout <secs>   Give up connecting after that long
+    --reconnect-timeout <secs> Give up reconnecting after losing connection for that long, 0 disables it
+    --known-hosts <path>       Where fingerprints of known servers are stored
+    --pin <fingerprint>        Only accept server with this certificate fingerprint, may be repeated
+    --ca-root <path>           Verify server certificate against this PEM CA root instead
+    --simulate-latency <ms>    Delay everything server sends by that much
+    --simulate-jitter <ms>     Random extra delay up to that
+    --simulate-loss <0-1>      Chance of a datagram from the server being lost
+    --simulate-reorder <0-1>   Chance of a datagram from the server arriving late
+    --simulate-bandwidth <B/s> Cap on what server can send
+    --simulate-queue <ms>      Datagrams that would wait longer than that for bandwidth are dropped
+    --simulate-seed <seed>     Same seed gives the same drops and delays";
+
+// Everything is optional in the file, missing fields are taken from Default
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub server_address: String,
+    // Used for SNI and certificate verification
+    pub server_name: String,
+    // Random one is picked if not set
+    pub player_name: Option<String>,
+    // In seconds
+    pub connect_timeout: u64,
+    // In seconds, counted from when the connection was lost. Zero doesn't reconnect at all
+    pub reconnect_timeout: u64,
+    // Certificate fingerprints of servers connected to before, see network::verifier
+    pub known_hosts: String,
+    // If not empty, only these fingerprints are accepted and known hosts are not used
+    pub pinned_fingerprints: Vec<String>,
+    // PEM file. If set, server certificate must be signed by it
+    pub ca_root: Option<String>,
+    // Bad network for testing, applied to what client receives. See shared::transport::simulated
+    pub simulate: shared::transport::simulated::Conditions,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            server_address: "185.161.210.210:1234".to_string(),
+            server_name: "recyclers-server".to_string(),
+            player_name: None,
+            connect_timeout: 10,
+            reconnect_timeout: 60,
+            known_hosts: "./known_hosts".to_string(),
+            pinned_fingerprints: vec![],
+            ca_root: None,
+            simulate: Default::default(),
+        }
+    }
+}
+
+impl Config {
+    // Reads config file and applies command line overrides on top of it
+    pub fn from_args() -> Result<Self, Box<dyn Error>> {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        let mut overrides = vec![];
+        let mut path = None;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let value = match args.next() {
+                Some(value) => value.clone(),
+                None => return Err(format!("Missing value for {}\n{}", arg, USAGE).into()),
+            };
+            if arg == "--config" {
+                path = Some(value);
+            } else {
+                overrides.push((arg.clone(), value));
+            }
+        }
+        let mut config = match path {
+            Some(path) => Self::load(std::path::Path::new(&path))?,
+            None => {
+                let path = std::path::Path::new(DEFAULT_PATH);
+                if path.exists() {
+                    Self::load(path)?
+                } else {
+                    Self::default()
+                }
+            }
+        };
+        for (arg, value) in overrides {
+            config.set(&arg, value)?;
+        }
+        config.simulate.check()?;
+        Ok(config)
+    }
+    pub fn load(path: &std::path::Path) -> Result<Self, Box<dyn Error>> {
+        let file = std::fs::File::open(path)
+            .map_err(|e| format!("Can't open config {}: {}", path.display(), e))?;
+        let reader = std::io::BufReader::new(file);
+        let config = serde_json::from_reader(reader)
+            .map_err(|e| format!("Can't parse config {}: {}", path.display(), e))?;
+        Ok(config)
+    }
+    pub fn player_name(&self) -> String {
+        self.player_name
+            .clone()
+            .unwrap_or_else(|| format!("player_{}", rand::random::<u16>()))
+    }
+    fn set(&mut self, arg: &str, value: String) -> Result<(), Box<dyn Error>> {
+        match arg {
+            "--server" => self.server_address = value,
+            "--server-name" => self.server_name = value,
+            "--name" => self.player_name = Some(value),
+            "--connect-timeout" => self.connect_timeout = parse(arg, &value)?,
+            "--reconnect-timeout" => self.reconnect_timeout = parse(arg, &value)?,
+            "--known-hosts" => self.known_hosts = value,
+            "--pin" => self.pinned_fingerprints.push(value),
+            "--ca-root" => self.ca_root = Some(value),
+            "--simulate-latency" => self.simulate.latency = parse(arg, &value)?,
+            "--simulate-jitter" => self.simulate.jitter = parse(arg, &value)?,
+            "--simulate-loss" => self.simulate.loss = parse(arg, &value)?,
+            "--simulate-reorder" => self.simulate.reorder = parse(arg, &value)?,
+            "--simulate-bandwidth" => self.simulate.bandwidth = Some(parse(arg, &value)?),
+            "--simulate-queue" => self.simulate.queue = parse(arg, &value)?,
+            "--simulate-seed" => self.simulate.seed = parse(arg, &value)?,
+            _ => return Err(format!("Unknown option {}\n{}", arg, USAGE).into()),
+        }
+        Ok(())
+    }
+}
+
+fn parse<T: std::str::FromStr>(arg: &str, value: &str) -> Result<T, Box<dyn Error>> {
+    Ok(value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", arg, value))?)
+}
@@ -1,8 +1,15 @@
+use crate::base::interpolation::{hold_unchanged, Interpolation, InterpolationBuffer};
+use crate::base::network::ServerCommand;
 use crate::base::planet::Planet;
+use crate::base::prediction::Predictor;
 use crate::base::systems::player_controller::PlayerData;
 use shared::EntityId;
 use std::collections::HashMap;
 
+// Ticks and position updates held while waiting for the snapshot. Only position updates are
+// dropped over it, older ones are superseded anyway
+const MAX_PENDING: usize = 256;
+
 #[derive(PartialEq, Eq, Hash, Clone)]
 pub enum InputType {
     KeyboardButton(glium::glutin::event::VirtualKeyCode),
@@ -10,6 +17,16 @@ pub enum InputType {
     Mouse(glium::glutin::event::MouseButton),
 }
 
+// Shown in the window title
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    // World is empty until the network thread gets back in
+    Reconnecting,
+    Disconnected(String),
+}
+
 #[derive(Default)]
 pub struct Input {
     pub keys_state: HashMap<InputType, bool>,
@@ -32,6 +49,7 @@ pub struct Character {
     pub entity: hecs::Entity,
     pub camera: hecs::Entity,
     pub player_data: PlayerData,
+    pub predictor: Predictor,
 }
 
 pub struct GameManager {
@@ -42,16 +60,28 @@ pub struct GameManager {
     pub input: Input,
 
     pub netclient: crate::base::network::Client,
+    pub network_stats: crate::base::network::NetworkStats,
+    pub connection: ConnectionState,
 
     pub state: shared::commands::ClientCommand,
     pub server_info: Option<shared::commands::ServerInfo>,
+    // Available once server info is received
+    pub codec: Option<shared::codec::TransformCodec>,
 
     pub time: f32,
     pub since_input_sent: std::time::Duration,
     pub delta: std::time::Duration,
     pub world: hecs::World,
     pub entity_ids: HashMap<EntityId, hecs::Entity>,
+    // Remote entities are rendered a bit behind the server, see interpolation.rs
+    pub interpolation: Interpolation,
     pub character: Option<Character>,
+    // Not rendered yet, kept so props can be told apart once they are
+    pub welds: Vec<shared::commands::Weld>,
+    // Tick the snapshot was taken at, once it has been applied completely
+    pub snapshot_tick: Option<u64>,
+    // World updates that arrived before the snapshot was applied
+    pub pending: Vec<ServerCommand>,
 }
 
 impl GameManager {
@@ -98,44 +128,200 @@ impl GameManager {
         }
 
         self.since_input_sent += self.delta;
+        let tickrate = self
+            .server_info
+            .as_ref()
+            .map(|info| info.tickrate)
+            .unwrap_or(60);
         if let Some(overflow) = self
             .since_input_sent
-            .checked_sub(std::time::Duration::from_secs(1) / 60)
+            .checked_sub(std::time::Duration::from_secs(1) / tickrate as u32)
         {
+            // Server applies each command for one tick, prediction relies on that
+            self.since_input_sent = overflow;
             self.run_player();
-            self.netclient.network_sender.send(self.state).unwrap();
+            self.state.sequence = self.state.sequence.wrapping_add(1);
+            self.state.tick_ack = self.network_stats.last_tick;
+            if let Some(character) = &mut self.character {
+                character.predictor.push(self.state);
+            }
+            // Network thread is gone once we got disconnected, nothing to send to
+            let _ = self.netclient.network_sender.send(self.state);
         }
         // Process server ticks
         while let Ok(command) = self.netclient.network_receiver.try_recv() {
-            use crate::base::network::ServerCommand::*;
-            match command {
-                Tick(tick) => {
-                    for (id, components) in tick.spawns {
-                        let mut builder = hecs::EntityBuilder::new();
-                        self.spawn(&mut builder, id, components);
+            self.on_server_command(command);
+        }
+        self.network_stats.update(&self.netclient);
+        self.network_stats.backlog = self
+            .character
+            .as_ref()
+            .map_or(0, |character| character.predictor.pending());
+        self.interpolate();
+        if let Some(character) = &self.character {
+            if let Ok(mut transform) = self
+                .world
+                .get_mut::<shared::components::Transform>(character.entity)
+            {
+                transform.isometry = character.predictor.predict();
+            }
+        }
+        shared::components::parent::update_children(&mut self.world);
+    }
+    fn on_server_command(&mut self, command: ServerCommand) {
+        use crate::base::network::ServerCommand::*;
+        // Counts as received even if it has to wait for the snapshot
+        let tick = match &command {
+            Snapshot(snapshot) => Some(snapshot.tick),
+            Tick(tick) => Some(tick.tick),
+            Positions(update) => Some(update.tick),
+            _ => None,
+        };
+        if let Some(tick) = tick {
+            self.network_stats.last_tick = self.network_stats.last_tick.max(tick);
+        }
+        if self.snapshot_tick.is_none() {
+            if let Tick(_) | Positions(_) = command {
+                // Ticks and position updates both wait here until the snapshot is applied. Ticks carry
+                // spawns and welds, so all of them are kept, only the oldest position updates make room
+                if self.pending.len() >= MAX_PENDING {
+                    if let Some(oldest) = self
+                        .pending
+                        .iter()
+                        .position(|command| matches!(command, Positions(_)))
+                    {
+                        self.pending.remove(oldest);
                     }
-                    for (id, isometry) in tick.positions {
-                        if let Some(entity) = self.entity_ids.get(&id) {
-                            if let Ok(mut transform) =
-                                self.world.get_mut::<shared::components::Transform>(*entity)
-                            {
-                                transform.isometry = isometry;
-                            }
+                }
+                self.pending.push(command);
+                return;
+            }
+        }
+        match command {
+            Snapshot(snapshot) => {
+                self.interpolation.clock.observe(snapshot.tick);
+                for (id, components) in snapshot.entities {
+                    let mut builder = hecs::EntityBuilder::new();
+                    self.spawn(&mut builder, id, components, snapshot.tick);
+                }
+                self.welds.extend(snapshot.welds);
+                if snapshot.last {
+                    println!("[CLIENT] Snapshot applied");
+                    self.snapshot_tick = Some(snapshot.tick);
+                    for command in std::mem::take(&mut self.pending) {
+                        self.on_server_command(command);
+                    }
+                }
+            }
+            Tick(tick) => {
+                // Already part of the snapshot
+                if Some(tick.tick) <= self.snapshot_tick {
+                    return;
+                }
+                self.interpolation.clock.observe(tick.tick);
+                for (id, components) in tick.spawns {
+                    let mut builder = hecs::EntityBuilder::new();
+                    self.spawn(&mut builder, id, components, tick.tick);
+                }
+                for id in tick.despawns {
+                    self.despawn(id);
+                }
+                // Despawned ends already took theirs along
+                self.welds
+                    .retain(|weld| !tick.unwelds.contains(&weld.ends()));
+                self.welds.extend(tick.welds);
+            }
+            Positions(update) => {
+                let codec = match &self.codec {
+                    Some(codec) => codec,
+                    None => return,
+                };
+                self.interpolation.clock.observe(update.tick);
+                let character = self.character.as_ref().map(|character| character.entity);
+                let mut character_position = None;
+                let updated = update.positions.iter().map(|(id, _)| *id).collect();
+                for (id, transform) in update.positions {
+                    let isometry = match codec.decode(&transform) {
+                        Some(isometry) => isometry,
+                        None => continue,
+                    };
+                    if let Some(entity) = self.entity_ids.get(&id) {
+                        if Some(*entity) == character {
+                            character_position = Some(isometry);
+                        } else if let Ok(mut buffer) =
+                            self.world.get_mut::<InterpolationBuffer>(*entity)
+                        {
+                            buffer.push(update.tick, isometry);
                         }
                     }
                 }
-                ServerInfoUpdate(info) => {
-                    println!("[CLIENT] {:?}", info);
-                    self.server_info = Some(info);
+                hold_unchanged(&self.world, update.tick, &updated);
+                self.network_stats.input_ack = self.network_stats.input_ack.max(update.input_ack);
+                if let Some(character) = &mut self.character {
+                    character.predictor.acknowledge(
+                        update.input_ack,
+                        update.tick,
+                        character_position,
+                    );
                 }
             }
+            Layers(layers) => {
+                println!("[CLIENT] Server has reloaded planet layers");
+                self.planet.set_layers(layers);
+            }
+            ServerInfoUpdate(info) => {
+                println!("[CLIENT] {:?}", info);
+                // Back after reconnecting, whatever is left over would be spawned twice by the snapshot
+                self.clear_world();
+                self.connection = ConnectionState::Connected;
+                self.codec = Some(shared::codec::TransformCodec::new(info.planet_radius));
+                self.interpolation.clock.tickrate = info.tickrate;
+                self.planet
+                    .set_terrain(info.planet_radius, info.planet_layers.clone());
+                self.server_info = Some(info);
+            }
+            Rejected(rejection) => {
+                println!("[CLIENT] Unable to join the server: {}", rejection.reason);
+                self.connection = ConnectionState::Disconnected(rejection.reason);
+            }
+            ConnectFailed(reason) => {
+                println!("[CLIENT] Unable to connect to the server: {}", reason);
+                self.connection = ConnectionState::Disconnected(reason);
+            }
+            Reconnecting(reason) => {
+                println!(
+                    "[CLIENT] Lost connection to the server, reconnecting: {}",
+                    reason
+                );
+                self.clear_world();
+                self.connection = ConnectionState::Reconnecting;
+            }
+            Disconnected(reason) => {
+                println!("[CLIENT] Lost connection to the server: {}", reason);
+                self.connection = ConnectionState::Disconnected(reason);
+            }
         }
-        shared::components::parent::update_children(&mut self.world);
     }
-    pub fn spawn_local_character(&mut self, entity: hecs::Entity) {
+    fn interpolate(&mut self) {
+        let tick = match self.interpolation.render_tick() {
+            Some(tick) => tick,
+            None => return,
+        };
+        let max_extrapolation = self.interpolation.max_extrapolation_ticks();
+        for (_, (buffer, transform)) in self
+            .world
+            .query::<(&mut InterpolationBuffer, &mut shared::components::Transform)>()
+            .iter()
+        {
+            transform.isometry = buffer.sample(tick, max_extrapolation);
+        }
+    }
+    pub fn spawn_local_character(&mut self, entity: hecs::Entity, predictor: Predictor) {
         use crate::base::components::*;
         use shared::components::*;
         println!("[CLIENT] Spawn local character!");
+        // Local character is predicted instead
+        let _ = self.world.remove_one::<InterpolationBuffer>(entity);
         let mut camera = hecs::EntityBuilder::new();
         camera.add(Camera::new());
         camera.add(Parent {
@@ -150,6 +336,7 @@ impl GameManager {
             entity,
             camera,
             player_data: PlayerData::default(),
+            predictor,
         });
     }
     pub fn spawn(
@@ -157,12 +344,14 @@ impl GameManager {
         builder: &mut hecs::EntityBuilder,
         id: EntityId,
         components: Vec<shared::commands::Component>,
+        tick: u64,
     ) {
         builder.add(id);
         for component in components {
             use shared::commands::Component::*;
             match component {
                 Transform(x) => {
+                    builder.add(InterpolationBuffer::new(tick, x.isometry));
                     builder.add(x);
                 }
                 Drawable(x) => {
@@ -174,9 +363,61 @@ impl GameManager {
         self.entity_ids.insert(id, e);
         if let Some(server_info) = &self.server_info {
             if server_info.character_id == id.0 {
-                self.spawn_local_character(e);
+                let position = self
+                    .world
+                    .get::<shared::components::Transform>(e)
+                    .map(|transform| transform.isometry)
+                    .unwrap_or_else(|_| na::Isometry3::identity());
+                let predictor = Predictor::new(
+                    server_info.planet_radius,
+                    server_info.tickrate,
+                    tick,
+                    position,
+                );
+                self.spawn_local_character(e, predictor);
             }
         }
         println!("[CLIENT] Spawn {}", id.0);
     }
+    pub fn despawn(&mut self, id: EntityId) {
+        use shared::components::Parent;
+        let entity = match self.entity_ids.remove(&id) {
+            Some(entity) => entity,
+            None => return,
+        };
+        // Children can't outlive their parent, update_children expects parent to exist
+        let children: Vec<hecs::Entity> = self
+            .world
+            .query::<&Parent>()
+            .iter()
+            .filter(|(_, child)| child.parent == entity)
+            .map(|(child, _)| child)
+            .collect();
+        for child in children {
+            let _ = self.world.despawn(child);
+        }
+        if let Some(character) = &self.character {
+            if character.entity == entity {
+                println!("[CLIENT] Local character was despawned");
+                self.character = None;
+            }
+        }
+        self.welds
+            .retain(|weld| weld.first != id && weld.second != id);
+        let _ = self.world.despawn(entity);
+        println!("[CLIENT] Despawn {}", id.0);
+    }
+    // Everything the server sent, a new snapshot brings it back
+    fn clear_world(&mut self) {
+        let ids: Vec<EntityId> = self.entity_ids.keys().copied().collect();
+        for id in ids {
+            self.despawn(id);
+        }
+        self.welds.clear();
+        self.pending.clear();
+        self.snapshot_tick = None;
+        self.interpolation.clock = Default::default();
+        self.network_stats.last_tick = 0;
+        self.network_stats.input_ack = 0;
+    }
 }
@@ -0,0 +1,213 @@
+// Remote entities are rendered slightly in the past, between two received snapshots, so network jitter doesn't show.
+// If snapshots stop coming, entities are extrapolated for a bit and then frozen
+
+use shared::EntityId;
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+// Snapshots older than that are useless, even with a huge delay
+const MAX_SNAPSHOTS: usize = 64;
+
+pub struct Interpolation {
+    // How far behind the newest snapshot entities are rendered
+    pub delay: Duration,
+    // How long entities keep moving after the last known snapshot
+    pub max_extrapolation: Duration,
+    pub clock: ServerClock,
+}
+
+impl Default for Interpolation {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_millis(100),
+            max_extrapolation: Duration::from_millis(250),
+            clock: ServerClock::default(),
+        }
+    }
+}
+
+impl Interpolation {
+    // Server tick entities should be rendered at, fractional
+    pub fn render_tick(&self) -> Option<f64> {
+        let tickrate = self.clock.tickrate as f64;
+        Some(self.clock.estimate()? - self.delay.as_secs_f64() * tickrate)
+    }
+    pub fn max_extrapolation_ticks(&self) -> f64 {
+        self.max_extrapolation.as_secs_f64() * self.clock.tickrate as f64
+    }
+}
+
+// Estimates which tick server is at right now
+pub struct ServerClock {
+    pub tickrate: u8,
+    start: Instant,
+    // Server tick minus local time in ticks, smoothed
+    offset: Option<f64>,
+}
+
+impl Default for ServerClock {
+    fn default() -> Self {
+        Self {
+            tickrate: 60,
+            start: Instant::now(),
+            offset: None,
+        }
+    }
+}
+
+impl ServerClock {
+    pub fn observe(&mut self, tick: u64) {
+        let sample = tick as f64 - self.local_ticks();
+        self.offset = Some(match self.offset {
+            // Jumps more than a second away are not jitter, server was restarted or we were stalled
+            Some(offset) if (sample - offset).abs() < self.tickrate as f64 => {
+                offset + (sample - offset) * 0.1
+            }
+            _ => sample,
+        });
+    }
+    pub fn estimate(&self) -> Option<f64> {
+        Some(self.offset? + self.local_ticks())
+    }
+    fn local_ticks(&self) -> f64 {
+        self.start.elapsed().as_secs_f64() * self.tickrate as f64
+    }
+}
+
+pub struct InterpolationBuffer {
+    // Sorted by tick
+    snapshots: VecDeque<(u64, na::Isometry3<f64>)>,
+}
+
+impl InterpolationBuffer {
+    pub fn new(tick: u64, isometry: na::Isometry3<f64>) -> Self {
+        let mut snapshots = VecDeque::with_capacity(8);
+        snapshots.push_back((tick, isometry));
+        Self { snapshots }
+    }
+    pub fn push(&mut self, tick: u64, isometry: na::Isometry3<f64>) {
+        // Datagrams may arrive out of order
+        let index = self
+            .snapshots
+            .iter()
+            .rposition(|(t, _)| *t <= tick)
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        if index > 0 && self.snapshots[index - 1].0 == tick {
+            self.snapshots[index - 1].1 = isometry;
+            return;
+        }
+        self.snapshots.insert(index, (tick, isometry));
+        if self.snapshots.len() > MAX_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+    }
+    // Entity was left out of the update for `tick`, so it's still where it was last time.
+    // Only counts for ticks after the newest snapshot, a late datagram says nothing about now
+    pub fn hold(&mut self, tick: u64) {
+        let (newest_tick, newest) = *self.snapshots.back().unwrap();
+        if tick > newest_tick {
+            self.push(tick, newest);
+        }
+    }
+    pub fn sample(&mut self, tick: f64, max_extrapolation: f64) -> na::Isometry3<f64> {
+        // Keep one snapshot before the render tick, older ones are not needed anymore
+        while self.snapshots.len() > 2 && (self.snapshots[1].0 as f64) <= tick {
+            self.snapshots.pop_front();
+        }
+        let (first_tick, first) = self.snapshots[0];
+        if tick <= first_tick as f64 || self.snapshots.len() == 1 {
+            return first;
+        }
+        let (second_tick, second) = self.snapshots[1];
+        if tick <= second_tick as f64 {
+            let t = (tick - first_tick as f64) / (second_tick - first_tick) as f64;
+            return interpolate(&first, &second, t);
+        }
+        // Ran out of snapshots, keep moving the same way for a while
+        let ahead = (tick - second_tick as f64).min(max_extrapolation);
+        let t = 1.0 + ahead / (second_tick - first_tick) as f64;
+        interpolate(&first, &second, t)
+    }
+}
+
+// Server leaves out entities that didn't move, see codec::DeltaFilter. Without a hold sample
+// an entity that came to rest would be extrapolated past the spot it stopped at
+pub fn hold_unchanged(world: &hecs::World, tick: u64, updated: &HashSet<EntityId>) {
+    for (_, (id, buffer)) in world
+        .query::<(&EntityId, &mut InterpolationBuffer)>()
+        .iter()
+    {
+        if !updated.contains(id) {
+            buffer.hold(tick);
+        }
+    }
+}
+
+// Linear for translation, spherical for rotation. Extrapolates for t > 1
+fn interpolate(a: &na::Isometry3<f64>, b: &na::Isometry3<f64>, t: f64) -> na::Isometry3<f64> {
+    let translation = a.translation.vector.lerp(&b.translation.vector, t);
+    let rotation = if t <= 1.0 {
+        a.rotation
+            .try_slerp(&b.rotation, t, 1.0e-9)
+            .unwrap_or(b.rotation)
+    } else {
+        let delta = b.rotation * a.rotation.inverse();
+        na::UnitQuaternion::from_scaled_axis(delta.scaled_axis() * (t - 1.0)) * b.rotation
+    };
+    na::Isometry3::from_parts(na::Translation3::from(translation), rotation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f64) -> na::Isometry3<f64> {
+        na::Isometry3::translation(x, 0.0, 0.0)
+    }
+
+    fn x(buffer: &mut InterpolationBuffer, tick: f64) -> f64 {
+        buffer.sample(tick, 15.0).translation.vector.x
+    }
+
+    #[test]
+    fn stopped_entity_is_not_extrapolated() {
+        let mut world = hecs::World::new();
+        let prop = world.spawn((EntityId(1), InterpolationBuffer::new(0, at(0.0))));
+        let other = world.spawn((EntityId(2), InterpolationBuffer::new(0, at(0.0))));
+        // Both move, then the prop stops and only the other one is in updates
+        for tick in 1..=10 {
+            let mut updated = HashSet::new();
+            updated.insert(EntityId(2));
+            world
+                .get_mut::<InterpolationBuffer>(other)
+                .unwrap()
+                .push(tick, at(tick as f64));
+            if tick <= 5 {
+                updated.insert(EntityId(1));
+                world
+                    .get_mut::<InterpolationBuffer>(prop)
+                    .unwrap()
+                    .push(tick, at(tick as f64));
+            }
+            hold_unchanged(&world, tick, &updated);
+        }
+        let mut prop = world.get_mut::<InterpolationBuffer>(prop).unwrap();
+        for tick in &[5.5, 8.0, 12.0] {
+            assert!((x(&mut prop, *tick) - 5.0).abs() < 1.0e-9, "tick {}", tick);
+        }
+        let mut other = world.get_mut::<InterpolationBuffer>(other).unwrap();
+        assert!((x(&mut other, 8.0) - 8.0).abs() < 1.0e-9);
+    }
+
+    #[test]
+    fn hold_only_counts_for_newer_ticks() {
+        let mut buffer = InterpolationBuffer::new(10, at(10.0));
+        // Reordered datagram from before
+        buffer.hold(5);
+        // Update came in two chunks, the one without the entity arrived first
+        buffer.hold(11);
+        buffer.push(11, at(11.0));
+        assert!((x(&mut buffer, 10.5) - 10.5).abs() < 1.0e-9);
+    }
+}
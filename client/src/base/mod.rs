@@ -1,10 +1,23 @@
+pub mod config;
+pub mod interpolation;
+pub mod network;
+pub mod prediction;
+
+// Everything that needs a window
+#[cfg(feature = "graphics")]
 pub mod components;
+#[cfg(feature = "graphics")]
 pub mod game_manager;
+#[cfg(feature = "graphics")]
 pub mod gltf_loader;
-pub mod network;
+#[cfg(feature = "graphics")]
 pub mod planet;
+#[cfg(feature = "graphics")]
 pub mod render;
+#[cfg(feature = "graphics")]
 pub mod systems;
+#[cfg(feature = "graphics")]
 pub mod textures;
 
+#[cfg(feature = "graphics")]
 pub use planet::*;
@@ -1,114 +1,434 @@
+mod verifier;
+
+use crate::base::config::Config;
+use futures::stream::BoxStream;
+use futures::{select, FutureExt};
 use futures_util::StreamExt;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use shared::commands::{HandshakeResponse, PositionUpdate, ServerMessage};
+use shared::network::NetworkError;
+use shared::transport::metered::{self, Rates, Traffic};
+use shared::transport::{simulated, Connection, NewConnection, RecvStream};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
+use verifier::{Trust, Verifier};
 
 #[derive(Debug)]
 pub enum ServerCommand {
+    // One chunk of the initial world state, see shared::commands::Snapshot
+    Snapshot(shared::commands::Snapshot),
     Tick(shared::commands::Tick),
+    Positions(PositionUpdate),
+    Layers(Vec<shared::planet::procgen::Layer>),
     ServerInfoUpdate(shared::commands::ServerInfo),
+    Rejected(shared::commands::ConnectRejected),
+    // Never got to the handshake
+    ConnectFailed(String),
+    // Connection was lost, world is cleared until the network thread gets back in
+    Reconnecting(String),
+    Disconnected(String),
+}
+
+#[derive(Debug)]
+pub enum ConnectError {
+    Resolve(String, std::io::Error),
+    NoAddress(String),
+    Endpoint(quinn::EndpointError),
+    Connect(quinn::ConnectError),
+    Connection(quinn::ConnectionError),
+    CaRoot(String),
+    // Server certificate didn't pass verification
+    Untrusted(String),
+    Timeout,
 }
 
+impl std::fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConnectError::Resolve(address, e) => write!(f, "can't resolve {}: {}", address, e),
+            ConnectError::NoAddress(address) => {
+                write!(f, "{} doesn't resolve to anything", address)
+            }
+            ConnectError::Endpoint(e) => write!(f, "can't create endpoint: {}", e),
+            ConnectError::Connect(e) => write!(f, "{}", e),
+            ConnectError::Connection(e) => write!(f, "{}", e),
+            ConnectError::CaRoot(reason) => write!(f, "{}", reason),
+            ConnectError::Untrusted(reason) => write!(f, "untrusted server: {}", reason),
+            ConnectError::Timeout => write!(f, "timed out"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
 pub struct Client {
     pub network_sender: mpsc::UnboundedSender<shared::commands::ClientCommand>,
     pub network_receiver: mpsc::UnboundedReceiver<ServerCommand>,
+    // Payload bytes and messages in both directions, kept across the whole session
+    pub traffic: Arc<Traffic>,
+    // Set by the network thread while connected
+    connection: Arc<Mutex<Option<Arc<dyn Connection>>>>,
+    // Disconnected once the network thread is done
+    done: std::sync::mpsc::Receiver<()>,
+}
+
+impl Client {
+    pub fn rtt(&self) -> Option<Duration> {
+        self.connection.lock().unwrap().as_ref()?.rtt()
+    }
+    // Finishes the command stream, so the server despawns our player right away instead of waiting
+    // for us to come back. Process is about to exit, so it only waits a moment for that to get through
+    pub fn leave(&mut self) {
+        let (closed, _) = mpsc::unbounded_channel();
+        self.network_sender = closed;
+        let _ = self.done.recv_timeout(LEAVE_TIMEOUT);
+    }
+}
+
+// Kept for a debug overlay, see GameManager::network_stats
+#[derive(Debug, Clone, Default)]
+pub struct NetworkStats {
+    pub rtt: Option<Duration>,
+    pub rates: Rates,
+    // Commands server hasn't confirmed yet
+    pub backlog: usize,
+    // Newest server tick anything arrived for
+    pub last_tick: u64,
+    // Last command server has confirmed
+    pub input_ack: u32,
+}
+
+impl NetworkStats {
+    // Rates are only resampled once a second, so they don't jump around every frame
+    pub fn update(&mut self, client: &Client) {
+        self.rtt = client.rtt();
+        if self.rates.elapsed() >= Duration::from_secs(1) {
+            self.rates.sample(&client.traffic);
+        }
+    }
 }
 
+// First attempt after losing the connection waits that long, every failed one doubles it
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(10);
+// Server that keeps taking us back only to drop us again is given up on after that many resumes in a row.
+// Connection that stayed up for long enough starts the count over
+const MAX_RESUMES: u32 = 5;
+const STABLE_CONNECTION: Duration = Duration::from_secs(30);
+const LEAVE_TIMEOUT: Duration = Duration::from_secs(1);
+
 async fn handle_out(
-    connection: quinn::Connection,
-    mut out_rx: mpsc::UnboundedReceiver<shared::commands::ClientCommand>,
-) {
+    connection: Arc<dyn Connection>,
+    out_rx: &mut mpsc::UnboundedReceiver<shared::commands::ClientCommand>,
+) -> Result<(), NetworkError> {
+    // One stream for the whole session, so commands arrive in order and don't pay for stream setup
+    let mut stream = connection.open_uni().await?;
     while let Some(command) = out_rx.recv().await {
-        let mut stream = connection.open_uni().await.unwrap();
-        shared::network::send(&mut stream, &command).await;
-        stream.finish().await.unwrap();
+        shared::network::send(&mut stream, &command).await?;
     }
+    shared::network::finish(&mut stream).await?;
+    Ok(())
 }
 
 #[tokio::main(core_threads = 1)]
 async fn connect(
+    config: Config,
     in_tx: mpsc::UnboundedSender<ServerCommand>,
-    out_rx: mpsc::UnboundedReceiver<shared::commands::ClientCommand>,
+    mut out_rx: mpsc::UnboundedReceiver<shared::commands::ClientCommand>,
+    traffic: Arc<Traffic>,
+    slot: Arc<Mutex<Option<Arc<dyn Connection>>>>,
 ) {
+    if config.simulate.is_active() {
+        println!(
+            "[CLIENT] Simulating network conditions: {:?}",
+            config.simulate
+        );
+    }
+    // Picked once, a random name shouldn't change when coming back
+    let name = config.player_name();
+    // Server hands out a new one every time it accepts us, it gets the same player back
+    let mut session = None;
+    // When the connection was lost and how long to wait before the next attempt
+    let mut lost: Option<(Instant, Duration)> = None;
+    let mut resumes = 0;
+    loop {
+        if let Some((since, delay)) = &mut lost {
+            if since.elapsed() >= Duration::from_secs(config.reconnect_timeout) {
+                println!("[CLIENT] Giving up on reconnecting");
+                let _ = in_tx.send(ServerCommand::Disconnected(
+                    "unable to reconnect".to_string(),
+                ));
+                return;
+            }
+            tokio::time::delay_for(*delay).await;
+            *delay = (*delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+        println!("[CLIENT] Connecting to {}...", config.server_address);
+        let connection = match establish(&config).await {
+            Ok(connection) => connection,
+            Err(e) if lost.is_some() => {
+                println!("[CLIENT] Failed to reconnect: {}", e);
+                continue;
+            }
+            Err(e) => {
+                println!(
+                    "[CLIENT] Failed to connect to {}: {}",
+                    config.server_address, e
+                );
+                let _ = in_tx.send(ServerCommand::ConnectFailed(e.to_string()));
+                return;
+            }
+        };
+        let mut connection: NewConnection = connection.into();
+        if config.simulate.is_active() {
+            connection = simulated::wrap(connection, &config.simulate, config.simulate.seed);
+        }
+        // Counts what the application gets, after the simulator
+        let mut connection = metered::wrap(connection, traffic.clone());
+        *slot.lock().unwrap() = Some(connection.connection.clone());
+        let mut joined_at = None;
+        let result = match join(&name, &mut connection, session, &in_tx).await {
+            Ok(Some(token)) => {
+                session = Some(token);
+                lost = None;
+                joined_at = Some(Instant::now());
+                play(connection, &in_tx, &mut out_rx).await
+            }
+            // Rejected, or the game is gone
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+        // Server was already told we're leaving if the command stream got finished
+        if let Some(previous) = slot.lock().unwrap().take() {
+            previous.close(b"disconnected");
+        }
+        let e = match result {
+            Ok(()) => return,
+            Err(e) => e,
+        };
+        // Never made it into the game, there is nothing to come back to.
+        // Server closing the connection or sending garbage won't get better by trying again either
+        if session.is_none() || config.reconnect_timeout == 0 || !e.is_lost() {
+            println!("[CLIENT] Disconnected from the server: {}", e);
+            let _ = in_tx.send(ServerCommand::Disconnected(e.to_string()));
+            return;
+        }
+        if lost.is_some() {
+            println!("[CLIENT] Failed to reconnect: {}", e);
+            continue;
+        }
+        if joined_at.map_or(false, |joined_at| joined_at.elapsed() >= STABLE_CONNECTION) {
+            resumes = 0;
+        }
+        resumes += 1;
+        if resumes > MAX_RESUMES {
+            println!("[CLIENT] Connection keeps dropping, giving up: {}", e);
+            let _ = in_tx.send(ServerCommand::Disconnected(format!(
+                "connection keeps dropping: {}",
+                e
+            )));
+            return;
+        }
+        println!("[CLIENT] Lost connection to the server: {}", e);
+        if in_tx
+            .send(ServerCommand::Reconnecting(e.to_string()))
+            .is_err()
+        {
+            return;
+        }
+        lost = Some((Instant::now(), RECONNECT_DELAY));
+    }
+}
+
+async fn establish(config: &Config) -> Result<quinn::NewConnection, ConnectError> {
+    // Network thread has nothing else to do, blocking lookup is fine
+    let address = config
+        .server_address
+        .to_socket_addrs()
+        .map_err(|e| ConnectError::Resolve(config.server_address.clone(), e))?
+        .next()
+        .ok_or_else(|| ConnectError::NoAddress(config.server_address.clone()))?;
     let mut endpoint = quinn::Endpoint::builder();
     let mut client_cfg = quinn::ClientConfig::default();
-    let tls_cfg = std::sync::Arc::get_mut(&mut client_cfg.crypto).unwrap();
-    tls_cfg
-        .dangerous()
-        .set_certificate_verifier(std::sync::Arc::new(AcceptAnyCertificate));
+    let tls_cfg = Arc::get_mut(&mut client_cfg.crypto).unwrap();
+    let mut failure = None;
+    if let Some(ca_root) = &config.ca_root {
+        // Regular verification, certificate has to be signed by the root and match the server name
+        let file = std::fs::File::open(ca_root)
+            .map_err(|e| ConnectError::CaRoot(format!("can't open CA root {}: {}", ca_root, e)))?;
+        match tls_cfg
+            .root_store
+            .add_pem_file(&mut std::io::BufReader::new(file))
+        {
+            Ok((added, _)) if added > 0 => {}
+            _ => {
+                return Err(ConnectError::CaRoot(format!(
+                    "no valid certificates in CA root {}",
+                    ca_root
+                )))
+            }
+        }
+    } else {
+        let trust = Trust::new(
+            config.pinned_fingerprints.clone(),
+            config.known_hosts.clone().into(),
+        );
+        let verifier = Verifier::new(config.server_address.clone(), trust);
+        failure = Some(verifier.failure());
+        tls_cfg
+            .dangerous()
+            .set_certificate_verifier(Arc::new(verifier));
+    }
     endpoint.default_client_config(client_cfg);
-    let (endpoint, _) = endpoint.bind(&"[::]:0".parse().unwrap()).unwrap();
+    let local_address = match address {
+        SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    };
+    let (endpoint, _) = endpoint
+        .bind(&local_address)
+        .map_err(ConnectError::Endpoint)?;
 
-    let mut connection = endpoint
-        .connect(
-            &"185.161.210.210:1234".parse::<SocketAddr>().unwrap(),
-            //&SocketAddr::new(IpAddr::V4(Ipv4Addr::new(185, 161, 210, 210)), 2454),
-            "recyclers-server",
-        )
-        .unwrap()
+    let connecting = endpoint
+        .connect(&address, &config.server_name)
+        .map_err(ConnectError::Connect)?;
+    let timeout = std::time::Duration::from_secs(config.connect_timeout);
+    tokio::time::timeout(timeout, connecting)
         .await
-        .unwrap();
-    let mut stream = connection.connection.open_uni().await.unwrap();
+        .map_err(|_| ConnectError::Timeout)?
+        .map_err(
+            |e| match failure.and_then(|failure| failure.lock().unwrap().take()) {
+                Some(reason) => ConnectError::Untrusted(reason),
+                None => ConnectError::Connection(e),
+            },
+        )
+}
+
+// Handshake and the initial snapshot. Returns the session to come back to, None if the game is over
+async fn join(
+    name: &str,
+    connection: &mut NewConnection,
+    session: Option<u64>,
+    in_tx: &mpsc::UnboundedSender<ServerCommand>,
+) -> Result<Option<u64>, NetworkError> {
+    let mut stream = connection.connection.open_uni().await?;
     println!("[CLIENT] Sending client info...");
-    shared::network::send(
-        &mut stream,
-        &shared::commands::ClientInfo {
-            name: String::from(format!("player_{}", rand::random::<u16>())),
-        },
-    )
-    .await;
-    stream.finish().await.unwrap();
+    let info = shared::commands::ClientInfo {
+        session,
+        ..shared::commands::ClientInfo::new(name.to_string())
+    };
+    shared::network::send(&mut stream, &info).await?;
+    shared::network::finish(&mut stream).await?;
 
     println!("[CLIENT] Waiting for server info...");
-    let mut stream = connection.uni_streams.next().await.unwrap().unwrap();
+    let mut stream = shared::network::accept_uni(&mut connection.uni_streams).await?;
 
-    let server_info = shared::network::receive::<shared::commands::ServerInfo>(&mut stream)
-        .await
-        .unwrap();
+    let server_info = match shared::network::receive::<HandshakeResponse>(&mut stream).await? {
+        HandshakeResponse::Accepted(server_info) => server_info,
+        HandshakeResponse::Rejected(rejection) => {
+            println!("[CLIENT] Server rejected connection: {}", rejection.reason);
+            let _ = in_tx.send(ServerCommand::Rejected(rejection));
+            return Ok(None);
+        }
+    };
 
-    in_tx
+    let session = server_info.session;
+    if in_tx
         .send(ServerCommand::ServerInfoUpdate(server_info))
-        .unwrap();
+        .is_err()
+    {
+        return Ok(None);
+    }
 
-    // TODO: separate snapshot and tick
-    let snapshot = shared::network::receive::<shared::commands::Tick>(&mut stream)
-        .await
-        .unwrap();
-    in_tx.send(ServerCommand::Tick(snapshot)).unwrap();
+    loop {
+        let chunk = shared::network::receive::<shared::commands::Snapshot>(&mut stream).await?;
+        let last = chunk.last;
+        if in_tx.send(ServerCommand::Snapshot(chunk)).is_err() {
+            return Ok(None);
+        }
+        if last {
+            break;
+        }
+    }
+    Ok(Some(session))
+}
 
-    tokio::spawn(handle_out(connection.connection, out_rx));
+// Runs until the game is gone, or the connection is lost
+async fn play(
+    connection: NewConnection,
+    in_tx: &mpsc::UnboundedSender<ServerCommand>,
+    out_rx: &mut mpsc::UnboundedReceiver<shared::commands::ClientCommand>,
+) -> Result<(), NetworkError> {
+    let NewConnection {
+        connection,
+        mut uni_streams,
+        mut datagrams,
+    } = connection;
+    let datagram_tx = in_tx.clone();
+    tokio::spawn(async move {
+        // Connection errors are reported by the ordered stream
+        while let Some(Ok(datagram)) = datagrams.next().await {
+            let update = match shared::network::decode_datagram::<PositionUpdate>(&datagram) {
+                Ok(update) => update,
+                Err(e) => {
+                    println!("[CLIENT] Dropping malformed datagram: {}", e);
+                    continue;
+                }
+            };
+            if datagram_tx.send(ServerCommand::Positions(update)).is_err() {
+                break;
+            }
+        }
+    });
 
-    let mut ordered = connection.uni_streams.next().await.unwrap().unwrap();
+    // Whatever piled up while reconnecting is for a world that's gone
+    while out_rx.try_recv().is_ok() {}
+    let sending = handle_out(connection, out_rx).fuse();
+    let receiving = receive_ordered(&mut uni_streams, in_tx).fuse();
+    futures::pin_mut!(sending, receiving);
+    // Whichever stops first, the other one has nothing left to do
+    select! {
+        result = sending => result,
+        result = receiving => result,
+    }
+}
+
+// Ok once the game is gone
+async fn receive_ordered(
+    uni_streams: &mut BoxStream<'static, Result<RecvStream, NetworkError>>,
+    in_tx: &mpsc::UnboundedSender<ServerCommand>,
+) -> Result<(), NetworkError> {
+    let mut ordered = shared::network::accept_uni(uni_streams).await?;
     loop {
-        let tick = shared::network::receive::<shared::commands::Tick>(&mut ordered)
-            .await
-            .unwrap();
-        in_tx.send(ServerCommand::Tick(tick)).unwrap();
+        let command = match shared::network::receive::<ServerMessage>(&mut ordered).await? {
+            ServerMessage::Tick(tick) => ServerCommand::Tick(tick),
+            ServerMessage::Positions(update) => ServerCommand::Positions(update),
+            ServerMessage::Layers(layers) => ServerCommand::Layers(layers),
+        };
+        if in_tx.send(command).is_err() {
+            return Ok(());
+        }
     }
 }
 
-pub fn spawn() -> Client {
+pub fn spawn(config: Config) -> Client {
     let (in_tx, in_rx) = mpsc::unbounded_channel();
     let (out_tx, out_rx) = mpsc::unbounded_channel();
+    let traffic = Arc::new(Traffic::default());
+    let connection = Arc::new(Mutex::new(None));
+    let connection_traffic = traffic.clone();
+    let slot = connection.clone();
+    let (done_tx, done) = std::sync::mpsc::channel();
     std::thread::spawn(move || {
-        connect(in_tx.clone(), out_rx);
+        connect(config, in_tx.clone(), out_rx, connection_traffic, slot);
+        drop(done_tx);
     });
 
     Client {
         network_sender: out_tx,
         network_receiver: in_rx,
-    }
-}
-
-struct AcceptAnyCertificate;
-
-impl rustls::ServerCertVerifier for AcceptAnyCertificate {
-    fn verify_server_cert(
-        &self,
-        _roots: &rustls::RootCertStore,
-        _presented_certs: &[rustls::Certificate],
-        _dns_name: webpki::DNSNameRef,
-        _ocsp_response: &[u8],
-    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
-        Ok(rustls::ServerCertVerified::assertion())
+        traffic,
+        connection,
+        done,
     }
 }
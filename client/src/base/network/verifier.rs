@@ -0,0 +1,204 @@
+// Servers use self-signed certificates, so there is no CA to check them against.
+// Instead fingerprint of the certificate is remembered on the first connection (trust on first use)
+// and every later connection to the same address has to present the same certificate
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+pub enum Trust {
+    // Fingerprints are stored in the known hosts file
+    FirstUse { known_hosts: PathBuf },
+    // Only these fingerprints are accepted
+    Pinned(Vec<String>),
+}
+
+impl Trust {
+    // Pinned fingerprints win, known hosts file isn't even looked at then
+    pub fn new(pinned: Vec<String>, known_hosts: PathBuf) -> Self {
+        if pinned.is_empty() {
+            Trust::FirstUse { known_hosts }
+        } else {
+            Trust::Pinned(pinned)
+        }
+    }
+}
+
+pub struct Verifier {
+    // Known hosts are keyed by address, every server uses the same certificate name
+    host: String,
+    trust: Trust,
+    // rustls error gets lost somewhere inside quinn, so the reason is kept here
+    failure: Arc<Mutex<Option<String>>>,
+}
+
+impl Verifier {
+    pub fn new(host: String, trust: Trust) -> Self {
+        Self {
+            host,
+            trust,
+            failure: Arc::new(Mutex::new(None)),
+        }
+    }
+    // Reason the certificate was refused, if it was
+    pub fn failure(&self) -> Arc<Mutex<Option<String>>> {
+        self.failure.clone()
+    }
+    fn check(&self, fingerprint: &str) -> Result<(), String> {
+        match &self.trust {
+            Trust::Pinned(pinned) => {
+                if pinned
+                    .iter()
+                    .any(|pinned| pinned.eq_ignore_ascii_case(fingerprint))
+                {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "certificate of {} ({}) is not one of the pinned fingerprints",
+                        self.host, fingerprint
+                    ))
+                }
+            }
+            Trust::FirstUse { known_hosts } => match lookup(known_hosts, &self.host)? {
+                Some(known) if known.eq_ignore_ascii_case(fingerprint) => Ok(()),
+                Some(known) => Err(format!(
+                    "certificate of {} has changed! Expected {}, got {}. \
+                     Someone may be impersonating the server. \
+                     If the server got a new certificate on purpose, remove its line from {}",
+                    self.host,
+                    known,
+                    fingerprint,
+                    known_hosts.display()
+                )),
+                None => {
+                    println!(
+                        "[CLIENT] First connection to {}, trusting certificate {}",
+                        self.host, fingerprint
+                    );
+                    remember(known_hosts, &self.host, fingerprint)
+                }
+            },
+        }
+    }
+}
+
+impl rustls::ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        _roots: &rustls::RootCertStore,
+        presented_certs: &[rustls::Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        let certificate = presented_certs
+            .first()
+            .ok_or(rustls::TLSError::NoCertificatesPresented)?;
+        let fingerprint = shared::network::fingerprint(&certificate.0);
+        match self.check(&fingerprint) {
+            Ok(()) => Ok(rustls::ServerCertVerified::assertion()),
+            Err(reason) => {
+                *self.failure.lock().unwrap() = Some(reason.clone());
+                Err(rustls::TLSError::General(reason))
+            }
+        }
+    }
+}
+
+// Each line is "<address> <fingerprint>"
+fn lookup(known_hosts: &PathBuf, host: &str) -> Result<Option<String>, String> {
+    let contents = match std::fs::read_to_string(known_hosts) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("can't read {}: {}", known_hosts.display(), e)),
+    };
+    Ok(contents
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            Some((parts.next()?, parts.next()?))
+        })
+        .find(|(known_host, _)| *known_host == host)
+        .map(|(_, fingerprint)| fingerprint.to_string()))
+}
+
+fn remember(known_hosts: &PathBuf, host: &str, fingerprint: &str) -> Result<(), String> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(known_hosts)
+        .map_err(|e| format!("can't open {}: {}", known_hosts.display(), e))?;
+    writeln!(file, "{} {}", host, fingerprint)
+        .map_err(|e| format!("can't write {}: {}", known_hosts.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "127.0.0.1:1234";
+
+    // Every test gets a file of its own, tests run in parallel
+    fn known_hosts(name: &str, contents: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("known_hosts_{}_{}", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn first_use(known_hosts: &std::path::Path) -> Verifier {
+        Verifier::new(
+            HOST.to_string(),
+            Trust::new(vec![], known_hosts.to_path_buf()),
+        )
+    }
+
+    #[test]
+    fn unknown_host_is_remembered() {
+        let path = known_hosts("unknown", "10.0.0.1:1234 aaaa\n");
+        assert_eq!(first_use(&path).check("bbbb"), Ok(()));
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents, format!("10.0.0.1:1234 aaaa\n{} bbbb\n", HOST));
+        // Same certificate is trusted from now on
+        assert_eq!(first_use(&path).check("BBBB"), Ok(()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn changed_certificate_is_rejected() {
+        let path = known_hosts("changed", &format!("{} aaaa\n", HOST));
+        assert!(first_use(&path).check("bbbb").is_err());
+        // Nothing is written over the old one
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents, format!("{} aaaa\n", HOST));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pinned_fingerprint_wins_over_known_hosts() {
+        let path = known_hosts("pinned", &format!("{} aaaa\n", HOST));
+        let trust = Trust::new(vec!["bbbb".to_string()], path.clone());
+        let verifier = Verifier::new(HOST.to_string(), trust);
+        assert_eq!(verifier.check("bbbb"), Ok(()));
+        assert!(verifier.check("aaaa").is_err());
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents, format!("{} aaaa\n", HOST));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn malformed_lines_are_skipped() {
+        let contents = format!("garbage\n\n{}\n{} aaaa\n", HOST, HOST);
+        let path = known_hosts("malformed", &contents);
+        assert_eq!(first_use(&path).check("aaaa"), Ok(()));
+        assert!(first_use(&path).check("bbbb").is_err());
+        // Line with no fingerprint doesn't count as a known host either
+        let path_without = known_hosts("malformed_only", &format!("{}\n", HOST));
+        assert_eq!(first_use(&path_without).check("bbbb"), Ok(()));
+        assert_eq!(
+            std::fs::read_to_string(&path_without).unwrap(),
+            format!("{}\n{} bbbb\n", HOST, HOST)
+        );
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&path_without).unwrap();
+    }
+}
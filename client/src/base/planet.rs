@@ -21,9 +21,14 @@ pub struct Planet {
     pub procgen: PlanetProcGen,
     pub heightmaps: HashMap<u32, ChunkData>,
     pub terrain_textures: glium::texture::SrgbTexture2dArray,
-    pub requests: Sender<(Chunk, u32)>,
-    // Chunk, heightmap, normalmap
-    pub output: Receiver<(Chunk, u32, Vec<i16>, Vec<i8>)>,
+    // Chunk, slot and the terrain generation it was requested with
+    pub requests: Sender<(Chunk, u32, u64)>,
+    // New radius and layers for the generation thread
+    pub terrain: Sender<(f64, Vec<Layer>)>,
+    // Chunk, slot, generation, heightmap, normalmap
+    pub output: Receiver<(Chunk, u32, u64, Vec<i16>, Vec<i8>)>,
+    // Bumped on every terrain change, chunks from older ones are stale
+    pub generation: u64,
     pub surface_cache: Cache,
     pub water_cache: Cache,
     pub clouds_cache: Cache,
@@ -31,17 +36,23 @@ pub struct Planet {
 
 impl Planet {
     pub fn new(radius: f64, textures: glium::texture::SrgbTexture2dArray) -> Self {
-        let (requests, requests_rx) = std::sync::mpsc::channel::<(Chunk, u32)>();
+        let (requests, requests_rx) = std::sync::mpsc::channel::<(Chunk, u32, u64)>();
         let (output_tx, output) = std::sync::mpsc::channel();
+        let (terrain, terrain_rx) = std::sync::mpsc::channel::<(f64, Vec<Layer>)>();
 
         // We create this thread to prevent render blocking when loading new surface areas
         std::thread::spawn(move || {
             // We cannot move/clone procgen, so we have to recreate it here
-            let mut procgen = PlanetProcGen::default();
+            // Flat until server sends its layers
+            let mut procgen = PlanetProcGen::from_layers(vec![]);
+            let mut radius = radius;
 
             loop {
-                let (chunk, slot) = requests_rx.recv().unwrap();
-                procgen.try_reload();
+                let (chunk, slot, generation) = requests_rx.recv().unwrap();
+                while let Ok((new_radius, layers)) = terrain_rx.try_recv() {
+                    radius = new_radius;
+                    procgen = PlanetProcGen::from_layers(layers);
+                }
                 // Capture samples
                 let mut heights: Vec<i16> = Vec::with_capacity(CHUNK_SAMPLES.pow(2) as usize);
                 let mut normals: Vec<i8> = Vec::with_capacity(CHUNK_SAMPLES.pow(2) as usize * 2);
@@ -79,24 +90,47 @@ impl Planet {
                     normals.push((normal_unit.y * 127.0) as i8);
                 }*/
 
-                output_tx.send((chunk, slot, heights, normals)).unwrap();
+                output_tx
+                    .send((chunk, slot, generation, heights, normals))
+                    .unwrap();
             }
         });
 
         Self {
             radius: radius,
-            procgen: PlanetProcGen::default(),
+            procgen: PlanetProcGen::from_layers(vec![]),
             // Should be enough.
             heightmaps: HashMap::with_capacity(1024),
             terrain_textures: textures,
             requests,
+            terrain,
             output,
+            generation: 0,
             surface_cache: Cache::new(15),
             water_cache: Cache::new(11),
             clouds_cache: Cache::new(5),
         }
     }
 
+    // Server decides how big the planet is, collision there has to match what's drawn here
+    pub fn set_terrain(&mut self, radius: f64, layers: Vec<Layer>) {
+        self.radius = radius;
+        self.set_layers(layers);
+    }
+
+    // Terrain has changed, every chunk has to be generated again
+    pub fn set_layers(&mut self, layers: Vec<Layer>) {
+        self.procgen = PlanetProcGen::from_layers(layers.clone());
+        self.generation += 1;
+        if self.terrain.send((self.radius, layers)).is_err() {
+            println!("[CLIENT] Planet generation thread has stopped, terrain is not updated");
+        }
+        self.surface_cache.clear();
+        for k in &mut self.surface_cache.used {
+            *k = false;
+        }
+    }
+
     pub fn height_at(&self, dir: na::Vector3<f64>, depth: u8) -> f64 {
         let p = na::Point::from(dir);
         self.procgen.get(p, depth)
@@ -180,10 +214,16 @@ impl Planet {
         self.clouds_cache.update();
     }
 
+    pub fn request(&self, chunk: Chunk, slot: u32) {
+        if self.requests.send((chunk, slot, self.generation)).is_err() {
+            println!("[CLIENT] Planet generation thread has stopped, chunk is not generated");
+        }
+    }
+
     pub fn allocate_chunks(&mut self) {
         for chunk in self.surface_cache.transfer.clone() {
             let slot = self.surface_cache.allocate(chunk).unwrap();
-            self.requests.send((chunk, slot)).unwrap();
+            self.request(chunk, slot);
         }
         for chunk in self.water_cache.transfer.clone() {
             let slot = self.water_cache.allocate(chunk).unwrap();
@@ -0,0 +1,268 @@
+// Local character is moved right away using the same rules the server uses (see shared::movement).
+// Inputs are kept until the server confirms them, then replayed on top of the authoritative position.
+// Server applies every command for exactly one tick and acks the one it applied, see shared::input.
+// When it can't (commands arrived late or in a burst), the position it sends fixes things up
+
+use shared::commands::ClientCommand;
+use std::collections::VecDeque;
+
+// Server that hasn't confirmed anything for that long is not going to
+const MAX_PENDING: usize = 256;
+
+pub struct Predictor {
+    planet_radius: f64,
+    // Each command is applied by the server for one tick, see shared::input
+    timestep: f64,
+    // Sent, but not yet confirmed by the server
+    pending: VecDeque<ClientCommand>,
+    // Authoritative position with all confirmed inputs applied
+    base: na::Isometry3<f64>,
+    last_tick: u64,
+}
+
+impl Predictor {
+    pub fn new(planet_radius: f64, tickrate: u8, tick: u64, position: na::Isometry3<f64>) -> Self {
+        Self {
+            planet_radius,
+            timestep: 1.0 / tickrate as f64,
+            pending: VecDeque::new(),
+            base: position,
+            last_tick: tick,
+        }
+    }
+    pub fn push(&mut self, command: ClientCommand) {
+        self.pending.push_back(command);
+        if self.pending.len() > MAX_PENDING {
+            let command = self.pending.pop_front().unwrap();
+            self.base = self.step(&self.base, &command);
+        }
+    }
+    // `sequence` is the last command server has applied at `tick`.
+    // Position is None if the server didn't send one, because the character didn't move enough
+    pub fn acknowledge(&mut self, sequence: u32, tick: u64, position: Option<na::Isometry3<f64>>) {
+        // Datagrams may arrive out of order
+        if tick < self.last_tick {
+            return;
+        }
+        self.last_tick = tick;
+        while let Some(command) = self.pending.front() {
+            if command.sequence > sequence {
+                break;
+            }
+            let command = self.pending.pop_front().unwrap();
+            self.base = self.step(&self.base, &command);
+        }
+        if let Some(position) = position {
+            self.base = position;
+        }
+    }
+    // Commands server hasn't confirmed yet
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+    pub fn predict(&self) -> na::Isometry3<f64> {
+        self.pending
+            .iter()
+            .fold(self.base, |position, command| self.step(&position, command))
+    }
+    fn step(&self, position: &na::Isometry3<f64>, command: &ClientCommand) -> na::Isometry3<f64> {
+        // Client doesn't know about ground contacts, so the character is assumed to stand on something.
+        // Jumps and falls are left to the server corrections
+        let movement = shared::movement::movement(
+            command,
+            &position.translation.vector,
+            self.planet_radius,
+            true,
+        );
+        let velocity = movement.velocity.unwrap_or_else(na::Vector3::zeros);
+        na::Isometry3::from_parts(
+            na::Translation3::from(position.translation.vector + velocity * self.timestep),
+            movement.rotation,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::input::{InputQueue, MAX_QUEUED_COMMANDS};
+    use std::collections::HashMap;
+
+    const RADIUS: f64 = 1275620.0;
+    const TICKRATE: u8 = 60;
+    // One way, in ticks
+    const LATENCY: u64 = 6;
+
+    enum Ack {
+        Full,
+        // Character didn't move enough to be included
+        WithoutPosition,
+        Lost,
+    }
+
+    fn command(sequence: u32) -> ClientCommand {
+        // Keep changing direction, so mistakes in replay order would show up
+        let angle = sequence as f64 / 20.0;
+        ClientCommand {
+            sequence,
+            tick_ack: 0,
+            movement_direction: na::Vector2::new(
+                (angle.cos() * 127.0) as i8,
+                (angle.sin() * 127.0) as i8,
+            ),
+            orientation: na::UnitQuaternion::from_euler_angles(0.0, 0.0, angle),
+            fly: false,
+            jump: false,
+            run: sequence % 50 < 25,
+            sit: false,
+            pickup: false,
+            prop_spawn: None,
+        }
+    }
+
+    fn error(a: &na::Isometry3<f64>, b: &na::Isometry3<f64>) -> f64 {
+        (a.translation.vector - b.translation.vector).norm() + a.rotation.angle_to(&b.rotation)
+    }
+
+    // Runs client and server for `ticks` with acks delayed by LATENCY. Commands sent at a tick arrive at `deliver_at(tick)`.
+    // Server takes commands from the same input queue it really uses and moves the character the way physics
+    // would on flat ground, `disturb` may push it around like collisions do.
+    // Returns what client predicted right after sending each command and where server put the character after applying it
+    fn simulate(
+        ticks: u64,
+        deliver_at: impl Fn(u64) -> u64,
+        ack: impl Fn(u64) -> Ack,
+        disturb: impl Fn(u64, &mut na::Isometry3<f64>),
+    ) -> (
+        HashMap<u32, na::Isometry3<f64>>,
+        HashMap<u32, na::Isometry3<f64>>,
+    ) {
+        let mut server = na::Isometry3::translation(RADIUS, 0.0, 0.0);
+        let mut last_sequence = 0;
+        let mut predictor = Predictor::new(RADIUS, TICKRATE, 0, server);
+        let mut input = InputQueue::default();
+        let mut commands_in_flight = VecDeque::new();
+        let mut acks_in_flight = VecDeque::new();
+        let mut predicted = HashMap::new();
+        let mut authoritative = HashMap::new();
+        for tick in 1..ticks {
+            let sequence = tick as u32;
+            predictor.push(command(sequence));
+            predicted.insert(sequence, predictor.predict());
+            commands_in_flight.push_back((deliver_at(tick), command(sequence)));
+
+            while let Some((arrival, _)) = commands_in_flight.front() {
+                if *arrival > tick {
+                    break;
+                }
+                let (_, command) = commands_in_flight.pop_front().unwrap();
+                input.push(command);
+            }
+            if let Some(command) = input.next() {
+                let movement =
+                    shared::movement::movement(&command, &server.translation.vector, RADIUS, true);
+                server.translation.vector += movement.velocity.unwrap() / TICKRATE as f64;
+                server.rotation = movement.rotation;
+                last_sequence = command.sequence;
+            }
+            disturb(tick, &mut server);
+            authoritative.insert(last_sequence, server);
+            match ack(tick) {
+                Ack::Full => {
+                    acks_in_flight.push_back((tick + LATENCY, tick, last_sequence, Some(server)))
+                }
+                Ack::WithoutPosition => {
+                    acks_in_flight.push_back((tick + LATENCY, tick, last_sequence, None))
+                }
+                Ack::Lost => {}
+            }
+
+            while let Some((arrival, ..)) = acks_in_flight.front() {
+                if *arrival > tick {
+                    break;
+                }
+                let (_, server_tick, sequence, position) = acks_in_flight.pop_front().unwrap();
+                predictor.acknowledge(sequence, server_tick, position);
+            }
+        }
+        (predicted, authoritative)
+    }
+
+    #[test]
+    fn prediction_matches_server() {
+        let (predicted, authoritative) =
+            simulate(300, |tick| tick + LATENCY, |_| Ack::Full, |_, _| {});
+        for sequence in 1..300 - LATENCY as u32 {
+            let error = error(&predicted[&sequence], &authoritative[&sequence]);
+            assert!(error < 1.0e-6, "command {} is off by {}", sequence, error);
+        }
+    }
+
+    #[test]
+    fn prediction_converges_after_correction() {
+        let (predicted, authoritative) = simulate(
+            300,
+            |tick| tick + LATENCY,
+            |_| Ack::Full,
+            |tick, position| {
+                if tick == 100 {
+                    position.translation.vector.y += 5.0;
+                }
+            },
+        );
+        // Server applied command 94 at tick 100, client couldn't know about the push until a round trip later
+        for sequence in 100 - LATENCY as u32..100 {
+            assert!(error(&predicted[&sequence], &authoritative[&sequence]) > 4.0);
+        }
+        for sequence in (100 + 2 * LATENCY as u32 + 1)..300 - LATENCY as u32 {
+            let error = error(&predicted[&sequence], &authoritative[&sequence]);
+            assert!(error < 1.0e-6, "command {} is off by {}", sequence, error);
+        }
+    }
+
+    #[test]
+    fn prediction_survives_lost_acks() {
+        let ack = |tick| match tick % 5 {
+            0 => Ack::Lost,
+            1 | 2 => Ack::WithoutPosition,
+            _ => Ack::Full,
+        };
+        let (predicted, authoritative) = simulate(300, |tick| tick + LATENCY, ack, |_, _| {});
+        for sequence in 1..300 - LATENCY as u32 {
+            let error = error(&predicted[&sequence], &authoritative[&sequence]);
+            assert!(error < 1.0e-6, "command {} is off by {}", sequence, error);
+        }
+    }
+
+    #[test]
+    fn prediction_recovers_from_late_commands() {
+        // Network stalls, then everything sent meanwhile arrives at once
+        let stall = 100..120;
+        let arrival = |tick| {
+            if stall.contains(&tick) {
+                stall.end + LATENCY
+            } else {
+                tick + LATENCY
+            }
+        };
+        let (predicted, authoritative) = simulate(300, arrival, |_| Ack::Full, |_, _| {});
+        // Server kept applying the last command it had, then skipped most of the burst
+        let last_before_stall = stall.start as u32 - 1;
+        assert!(
+            error(
+                &predicted[&last_before_stall],
+                &authoritative[&last_before_stall]
+            ) > 1.0
+        );
+        for sequence in stall.start as u32..stall.end as u32 - MAX_QUEUED_COMMANDS as u32 {
+            assert!(!authoritative.contains_key(&sequence));
+        }
+        // One round trip after the burst is sorted out, prediction is exact again.
+        // What's left of the burst stays queued, so server is a few commands further behind than before
+        let recovered = (stall.end + 3 * LATENCY + MAX_QUEUED_COMMANDS as u64) as u32;
+        for sequence in recovered..300 - (LATENCY as u32 + MAX_QUEUED_COMMANDS as u32) {
+            let error = error(&predicted[&sequence], &authoritative[&sequence]);
+            assert!(error < 1.0e-6, "command {} is off by {}", sequence, error);
+        }
+    }
+}
@@ -295,17 +295,16 @@ impl Planet {
         time: f32,
         camera: hecs::Entity,
     ) {
-        if self.procgen.try_reload() {
-            self.surface_cache.clear();
-            for k in &mut self.surface_cache.used {
-                *k = false;
-            }
-        }
         let camera_transform = *world.get::<Transform>(camera).unwrap();
         self.update_cache(camera_transform.isometry.translation.vector);
         self.allocate_chunks();
 
-        for (chunk, slot, heightmap, normalmap) in self.output.try_recv() {
+        for (chunk, slot, generation, heightmap, normalmap) in self.output.try_recv() {
+            // Terrain has changed since it was requested, the slot still waits for it
+            if generation != self.generation {
+                self.request(chunk, slot);
+                continue;
+            }
             self.surface_cache.release(slot);
 
             // Generate heightmap
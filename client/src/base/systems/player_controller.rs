@@ -12,7 +12,8 @@ impl Default for PlayerData {
         Self {
             speed: 10.0,
             orientation: na::UnitQuaternion::identity(),
-            fly: true,
+            // Server may not allow it, see ServerInfo::allow_fly
+            fly: false,
             camera_angles: (0.0, 0.0),
         }
     }
@@ -24,6 +25,10 @@ impl GameManager {
         use winit::event::MouseButton;
         use winit::event::VirtualKeyCode::*;
         let input = &self.input;
+        let allow_fly = self
+            .server_info
+            .as_ref()
+            .map_or(false, |info| info.allow_fly);
         let mut player_data = if let Some(character) = &mut self.character {
             &mut character.player_data
         } else {
@@ -77,7 +82,7 @@ impl GameManager {
             .input
             .was_pressed(&InputType::Mouse(MouseButton::Right))
         {
-            if state == &true {
+            if state == &true && allow_fly {
                 player_data.fly = !player_data.fly;
             }
         }
@@ -106,12 +111,15 @@ impl GameManager {
 
         // Update state
         self.state = shared::commands::ClientCommand {
+            sequence: self.state.sequence,
+            tick_ack: self.state.tick_ack,
             movement_direction: na::Vector2::new(
                 movement_direction.x as i8,
                 movement_direction.y as i8,
             ),
             orientation: player_data.orientation, //self.orientation,
-            fly: player_data.fly,
+            // Permission may be gone after reconnecting
+            fly: player_data.fly && allow_fly,
             run,
             jump,
             sit,
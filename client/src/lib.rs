@@ -0,0 +1,2 @@
+extern crate nalgebra as na;
+pub mod base;
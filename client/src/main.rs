@@ -1,22 +1,25 @@
 extern crate nalgebra as na;
-pub mod base;
 
-use base::game_manager::GameManager;
-use base::render::backend::BackEnd;
-use base::render::build_glutin_window;
-use base::systems;
+use planet_gen::base;
+use planet_gen::base::game_manager::{ConnectionState, GameManager};
+use planet_gen::base::render::backend::BackEnd;
+use planet_gen::base::render::build_glutin_window;
 
 use glium::glutin;
 use hecs::World;
 
 fn main() {
-    std::thread::spawn(move || {
-        //server::run();
-    });
-
-    let netclient = base::network::spawn();
+    let config = match base::config::Config::from_args() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("[CLIENT] {}", e);
+            std::process::exit(1);
+        }
+    };
+    let netclient = base::network::spawn(config);
     let world = World::new();
 
+    // Until the server says how big the planet is, see Planet::set_terrain
     let planet_radius = 1275620.0;
 
     let (window_builder, event_loop) = build_glutin_window(1920., 1080., "Silicon Postlive");
@@ -40,16 +43,21 @@ fn main() {
         input: Default::default(),
         planet: planet,
         netclient,
+        network_stats: Default::default(),
+        connection: ConnectionState::Connecting,
         world,
         time: 0.0,
         entity_ids: std::collections::HashMap::new(),
+        interpolation: Default::default(),
         since_input_sent: std::time::Duration::new(0, 0),
         delta: std::time::Duration::new(0, 0),
         // TODO: Implement default
         state: shared::commands::ClientCommand {
+            sequence: 0,
+            tick_ack: 0,
             movement_direction: na::Vector2::repeat(127),
             orientation: na::UnitQuaternion::from_euler_angles(0.0, 0.0, 0.0),
-            fly: true,
+            fly: false,
             run: false,
             jump: false,
             sit: false,
@@ -57,14 +65,27 @@ fn main() {
             prop_spawn: None,
         },
         server_info: None,
+        codec: None,
         character: None,
+        welds: vec![],
+        snapshot_tick: None,
+        pending: vec![],
     };
 
     let start = std::time::Instant::now();
+    let mut shown_connection = game_manager.connection.clone();
 
     glium_backend.render(&mut game_manager);
-    event_loop.run(move |event, _, _control_flow| {
+    event_loop.run(move |event, _, control_flow| {
         match event {
+            glutin::event::Event::WindowEvent {
+                event: glutin::event::WindowEvent::CloseRequested,
+                ..
+            } => {
+                // Event loop never returns, nothing gets dropped on the way out
+                game_manager.netclient.leave();
+                *control_flow = glutin::event_loop::ControlFlow::Exit;
+            }
             glutin::event::Event::WindowEvent { event, .. } => {
                 game_manager.window_events.push(event.to_static().unwrap());
             }
@@ -72,6 +93,14 @@ fn main() {
                 let frame_start = std::time::Instant::now();
                 game_manager.time = start.elapsed().as_secs_f32();
                 game_manager.run();
+                if game_manager.connection != shown_connection {
+                    shown_connection = game_manager.connection.clone();
+                    glium_backend
+                        .display
+                        .gl_window()
+                        .window()
+                        .set_title(&window_title(&shown_connection));
+                }
                 glium_backend.render(&mut game_manager);
                 game_manager.window_events.clear();
                 //glium_backend.request_redraw();
@@ -81,3 +110,13 @@ fn main() {
         };
     });
 }
+
+fn window_title(connection: &ConnectionState) -> String {
+    match connection {
+        ConnectionState::Connecting | ConnectionState::Connected => "Silicon Postlive".to_string(),
+        ConnectionState::Reconnecting => "Silicon Postlive (reconnecting...)".to_string(),
+        ConnectionState::Disconnected(reason) => {
+            format!("Silicon Postlive (disconnected: {})", reason)
+        }
+    }
+}
@@ -1,7 +1,9 @@
 use crate::base::components::physics::PhysicsBody;
 use crate::base::player::Player;
+use crate::base::props::pickable::PickAble;
 use crate::base::props::PropData;
 use crate::base::systems::physics::Physics;
+use crate::base::validation::{Validation, Violation};
 
 use hecs::Entity;
 use rand::rngs::SmallRng;
@@ -14,78 +16,143 @@ pub struct GameManager {
     pub physics: Physics,
     pub entity_ids: HashMap<EntityId, Entity>,
     pub props: HashMap<usize, PropData>,
-    spawns: Vec<Entity>,
+    // Props fixed together by players, replicated along with entities, see base::interest
+    pub welds: Vec<shared::commands::Weld>,
+    despawns: Vec<EntityId>,
+    // Every new player starts with a copy of it
+    validation: Validation,
+    violations: Vec<(Entity, Violation)>,
     rng: SmallRng,
 }
 
 impl GameManager {
-    pub fn new() -> Self {
-        GameManager {
+    pub fn new(config: &crate::config::Config) -> Result<Self, anyhow::Error> {
+        use anyhow::Context;
+        let procgen = shared::planet::procgen::PlanetProcGen::from_file(&config.planet.layers)
+            .map_err(|e| anyhow::anyhow!("{}", e))
+            .with_context(|| format!("Can't load planet {}", config.planet.layers))?;
+        let mut game = GameManager {
             world: hecs::World::new(),
-            physics: Physics::new(),
+            physics: Physics::new(config, procgen),
             entity_ids: HashMap::with_capacity(2048),
-            spawns: Vec::with_capacity(256),
+            despawns: Vec::with_capacity(256),
+            validation: Validation::new(&config.validation, config.tickrate),
+            violations: vec![],
             rng: SmallRng::from_entropy(),
             props: HashMap::new(),
-        }
+            welds: vec![],
+        };
+        game.load_props(std::path::Path::new(&config.props_directory))?;
+        Ok(game)
     }
-    pub fn step(
-        &mut self,
-    ) -> (
-        Vec<(EntityId, Vec<Component>)>,
-        Vec<(EntityId, na::Isometry3<f64>)>,
-    ) {
-        self.physics.run(&mut self.world);
-
+    // Spawns are not returned, they are picked up by interest management, see base::interest
+    pub fn step(&mut self) -> (Vec<EntityId>, Vec<(EntityId, na::Isometry3<f64>)>) {
+        for (entity, player) in self.world.query::<&mut Player>().iter() {
+            player.state = player.input.next();
+            if let Some(state) = &mut player.state {
+                for violation in player.validation.check(state, &self.props) {
+                    self.violations.push((entity, violation));
+                }
+            }
+        }
+        // Commands go in before physics runs, so positions sent with an ack already include the acked command
         let mut props = vec![];
-        self.manage_pickables();
-        self.manage_welds();
         for (_entity, (player, physics_body)) in
             self.world.query::<(&mut Player, &PhysicsBody)>().iter()
         {
-            if player.state.is_none() {
-                continue;
+            let state = match player.state {
+                Some(state) => state,
+                None => continue,
             };
             let planet_handle = self.physics.planet_handle.clone();
             player.walk(&mut self.physics, physics_body, planet_handle);
-            if let Some(prop) = player.state.unwrap().prop_spawn {
+            player.last_sequence = state.sequence;
+            if let Some(prop) = state.prop_spawn {
                 props.push((prop, physics_body.clone()));
             }
-            player.state = None;
         }
+        self.physics.run(&mut self.world);
+
+        self.manage_pickables();
+        self.manage_welds();
         for (prop, owner) in props {
             self.spawn_prop(&owner, prop as usize);
         }
-        let mut new_spawns = Vec::with_capacity(self.spawns.len());
         let mut positions = vec![];
-        for entity in self.spawns.drain(..) {
-            let entity_id = *self.world.get::<EntityId>(entity).unwrap();
-            new_spawns.push((entity_id, pull_components(&self.world, entity)));
-        }
         for (_entity, (&id, &transform)) in &mut self.world.query::<(&EntityId, &Transform)>() {
             positions.push((id, transform.isometry));
         }
-        (new_spawns, positions)
+        let despawns = self.despawns.drain(..).collect();
+        (despawns, positions)
     }
     pub fn spawn_player(&mut self, info: shared::commands::ClientInfo) -> (EntityId, hecs::Entity) {
         let id = self.new_id();
-        let player = crate::base::player::spawn(&mut self.world, &mut self.physics, info.name, id);
+        let player = crate::base::player::spawn(
+            &mut self.world,
+            &mut self.physics,
+            info.name,
+            id,
+            self.validation.clone(),
+        );
         self.spawn(player);
         (id, player)
     }
+    // Player stays where it is, holding whatever it held, until its client comes back
+    pub fn detach_player(&mut self, entity: Entity) {
+        if let Ok(mut player) = self.world.get_mut::<Player>(entity) {
+            player.input = Default::default();
+        }
+    }
+    pub fn despawn_player(&mut self, entity: Entity) {
+        let picked_object = self
+            .world
+            .get_mut::<Player>(entity)
+            .ok()
+            .and_then(|mut player| player.picked_object.take());
+        if let Some(picked_object) = picked_object {
+            if let Ok(mut pickable) = self.world.get_mut::<PickAble>(picked_object) {
+                pickable.owner = None;
+            }
+        }
+        self.despawn(entity);
+    }
     pub fn spawn(&mut self, entity: Entity) {
-        self.spawns.push(entity);
         let id = self.world.get::<EntityId>(entity);
         if let Ok(id) = id {
             self.entity_ids.insert(*id, entity);
         }
     }
-    pub fn snapshot(&mut self) -> Vec<(EntityId, Vec<Component>)> {
-        let mut entities = vec![];
-        for (entity, &id) in &mut self.world.query::<&EntityId>() {
-            entities.push((id, pull_components(&self.world, entity)));
+    // Removes entity from the world along with its physics body. Clients are notified on the next step
+    pub fn despawn(&mut self, entity: Entity) {
+        if let Ok(id) = self.world.get::<EntityId>(entity).map(|id| *id) {
+            self.entity_ids.remove(&id);
+            self.despawns.push(id);
+            // Joints themselves are removed along with the body
+            self.welds
+                .retain(|weld| weld.first != id && weld.second != id);
         }
-        entities
+        if let Ok(handle) = self
+            .world
+            .get::<PhysicsBody>(entity)
+            .map(|body| body.handle)
+        {
+            self.physics.remove_body(handle);
+            for (_, physics_body) in self.world.query::<&mut PhysicsBody>().iter() {
+                physics_body
+                    .collides_with
+                    .retain(|(_, body_handle)| *body_handle != handle);
+            }
+        }
+        for (_, player) in self.world.query::<&mut Player>().iter() {
+            if player.picked_object == Some(entity) {
+                player.picked_object = None;
+            }
+        }
+        let _ = self.world.despawn(entity);
+    }
+    // Commands players sent that had to be fixed up since the last call
+    pub fn drain_violations(&mut self) -> Vec<(Entity, Violation)> {
+        self.violations.drain(..).collect()
     }
     pub fn new_id(&mut self) -> EntityId {
         loop {
@@ -100,7 +167,7 @@ impl GameManager {
 
 // NOTE: It doesn't looks like the best way to do it. But it'll work for now, we don't have lots of components
 // Maybe I can make it into macro or smthng
-fn pull_components(world: &hecs::World, entity: Entity) -> Vec<Component> {
+pub fn pull_components(world: &hecs::World, entity: Entity) -> Vec<Component> {
     let mut components = Vec::new();
     if let Ok(x) = world.get::<Transform>(entity) {
         components.push(Component::Transform((*x).clone()));
@@ -110,3 +177,62 @@ fn pull_components(world: &hecs::World, entity: Entity) -> Vec<Component> {
     }
     components
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::commands::{ClientCommand, ClientInfo};
+
+    // Default config points to ./assets of the workspace
+    fn game() -> GameManager {
+        let root = concat!(env!("CARGO_MANIFEST_DIR"), "/..");
+        let mut config = crate::config::Config::default();
+        config.planet.layers = format!("{}/assets/planet.json", root);
+        config.props_directory = format!("{}/assets/props", root);
+        GameManager::new(&config).unwrap()
+    }
+
+    fn command(sequence: u32) -> ClientCommand {
+        ClientCommand {
+            sequence,
+            tick_ack: 0,
+            movement_direction: na::Vector2::new(0, 127),
+            orientation: na::UnitQuaternion::identity(),
+            // Moves the player whether it's on the ground or not
+            fly: true,
+            jump: false,
+            run: true,
+            sit: false,
+            pickup: false,
+            prop_spawn: None,
+        }
+    }
+
+    fn position(game: &GameManager, entity: Entity) -> na::Vector3<f64> {
+        game.world
+            .get::<Transform>(entity)
+            .unwrap()
+            .isometry
+            .translation
+            .vector
+    }
+
+    #[test]
+    fn acked_command_is_in_the_same_step_positions() {
+        let (mut moving, mut idle) = (game(), game());
+        let (_, player) = moving.spawn_player(ClientInfo::new("moving".to_string()));
+        let (_, idle_player) = idle.spawn_player(ClientInfo::new("idle".to_string()));
+        moving
+            .world
+            .get_mut::<Player>(player)
+            .unwrap()
+            .input
+            .push(command(1));
+        moving.step();
+        idle.step();
+        assert_eq!(moving.world.get::<Player>(player).unwrap().last_sequence, 1);
+        // Worlds are the same otherwise, so only the acked command could have moved the player
+        let moved = (position(&moving, player) - position(&idle, idle_player)).norm();
+        assert!(moved > 1e-3, "moved {}", moved);
+    }
+}
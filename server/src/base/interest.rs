@@ -0,0 +1,82 @@
+// Area of interest. Each player only receives entities that are close enough to them
+
+use crate::base::game_manager::{pull_components, GameManager};
+use hecs::Entity;
+use shared::{
+    commands::{Component, Weld},
+    components::Transform,
+    EntityId,
+};
+use std::collections::HashSet;
+
+// Entities closer than that are replicated to the player
+pub const INTEREST_RADIUS: f64 = 5000.0;
+// Entities are dropped a bit further away, so ones moving along the border don't get respawned every tick
+const INTEREST_HYSTERESIS: f64 = 1.2;
+
+#[derive(Default)]
+pub struct Interest {
+    entities: HashSet<EntityId>,
+    welds: HashSet<(EntityId, EntityId)>,
+}
+
+impl Interest {
+    pub fn contains(&self, id: &EntityId) -> bool {
+        self.entities.contains(id)
+    }
+}
+
+impl GameManager {
+    // Updates set of entities replicated to the player. Returns ones that entered and left it
+    // NOTE: Goes through every entity for every player. Fine for now, but will need a spatial index eventually
+    pub fn update_interest(
+        &self,
+        player: Entity,
+        interest: &mut Interest,
+    ) -> (Vec<(EntityId, Vec<Component>)>, Vec<EntityId>) {
+        let center = match self.world.get::<Transform>(player) {
+            Ok(transform) => transform.isometry.translation.vector,
+            Err(_) => return (vec![], vec![]),
+        };
+        let mut spawns = vec![];
+        let mut visible = HashSet::with_capacity(interest.entities.len());
+        for (entity, (&id, transform)) in &mut self.world.query::<(&EntityId, &Transform)>() {
+            let known = interest.entities.contains(&id);
+            let radius = if known {
+                INTEREST_RADIUS * INTEREST_HYSTERESIS
+            } else {
+                INTEREST_RADIUS
+            };
+            let distance = (transform.isometry.translation.vector - center).norm();
+            if entity != player && distance > radius {
+                continue;
+            }
+            if !known {
+                spawns.push((id, pull_components(&self.world, entity)));
+            }
+            visible.insert(id);
+        }
+        // Also covers entities that no longer exist
+        let despawns = interest.entities.difference(&visible).cloned().collect();
+        interest.entities = visible;
+        (spawns, despawns)
+    }
+    // Updates set of welds player can see both ends of. Returns ones that appeared and ones that are gone,
+    // so call it after update_interest
+    pub fn update_welds(&self, interest: &mut Interest) -> (Vec<Weld>, Vec<(EntityId, EntityId)>) {
+        let mut welds = vec![];
+        let mut visible = HashSet::with_capacity(interest.welds.len());
+        for weld in &self.welds {
+            if !interest.contains(&weld.first) || !interest.contains(&weld.second) {
+                continue;
+            }
+            if !interest.welds.contains(&weld.ends()) {
+                welds.push(weld.clone());
+            }
+            visible.insert(weld.ends());
+        }
+        let unwelds = interest.welds.difference(&visible).cloned().collect();
+        interest.welds = visible;
+        (welds, unwelds)
+    }
+}
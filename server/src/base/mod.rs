@@ -1,6 +1,8 @@
 pub mod components;
 pub mod game_manager;
 pub mod gltf_loader;
+pub mod interest;
 pub mod player;
 pub mod props;
 pub mod systems;
+pub mod validation;
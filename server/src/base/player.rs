@@ -11,18 +11,31 @@ use nphysics3d::object::{
 
 pub struct Player {
     pub name: String,
+    // Commands waiting for their tick, see shared::input
+    pub input: shared::input::InputQueue,
+    // Input applied during the current tick, already validated
     pub state: Option<shared::commands::ClientCommand>,
+    pub validation: crate::base::validation::Validation,
     pub picked_object: Option<hecs::Entity>,
+    // Sequence number of the last applied command, echoed back to the client
+    pub last_sequence: u32,
     pub ground_sensor: DefaultColliderHandle,
 }
 
 impl Player {
-    pub fn new(name: String, ground_sensor: DefaultColliderHandle) -> Self {
+    pub fn new(
+        name: String,
+        ground_sensor: DefaultColliderHandle,
+        validation: crate::base::validation::Validation,
+    ) -> Self {
         Self {
             name,
             ground_sensor,
+            validation,
+            input: Default::default(),
             state: None,
             picked_object: None,
+            last_sequence: 0,
         }
     }
     pub fn walk(
@@ -39,6 +52,7 @@ impl Player {
             }
         };
         let on_surface = handle.collides_with.len() > 0;
+        let planet_radius = physics.planet_radius;
         let body = physics
             .bodies
             .get_mut(handle.handle)
@@ -46,64 +60,29 @@ impl Player {
             .downcast_mut::<RigidBody<f64>>()
             .unwrap();
 
-        // Convert from i8 to float. Normalize to avoid cheating
-        let mut movement_direction = na::Vector3::new(
-            state.movement_direction.x as f64,
-            0.0,
-            -state.movement_direction.y as f64,
-        )
-        .try_normalize(0.5)
-        .unwrap_or(na::Vector3::repeat(0.0));
-
-        if state.run {
-            movement_direction *= 2.0;
-        }
-        if state.sit {
-            movement_direction *= 0.2;
-        }
-        if state.jump && state.sit {
-            movement_direction.y = -1.0;
-        }
         let mut position = *body.position();
-        // If we assume that planet origin is zero
-        let q = na::UnitQuaternion::face_towards(&position.translation.vector, &na::Vector3::z());
-        position.rotation = na::convert(q * state.orientation);
+        let movement = shared::movement::movement(
+            &state,
+            &position.translation.vector,
+            planet_radius,
+            handle.on_surface || on_surface,
+        );
+        position.rotation = movement.rotation;
         body.set_position(position);
 
-        let mut movement_direction_transformed = q.transform_vector(&movement_direction.xzy());
-        let altitude = body.position().translation.vector.norm() - 1275620 as f64;
         let player_velocity = body.velocity().linear;
-        let up = q.transform_vector(&na::Vector3::new(0.0, 0.0, 1.0));
-
-        if state.fly {
-            if state.jump && !state.sit {
-                movement_direction_transformed += up;
-            }
+        if let Some(jump_force) = movement.jump_force {
             body.apply_force(
                 0,
-                &nphysics3d::math::Force::new(
-                    movement_direction_transformed * altitude.abs().max(1.0) - player_velocity,
-                    na::zero(),
-                ),
-                nphysics3d::algebra::ForceType::VelocityChange,
+                &nphysics3d::math::Force::new(jump_force, na::zero()),
+                nphysics3d::algebra::ForceType::Force,
                 true,
             );
-        } else if handle.on_surface || on_surface {
-            if state.jump {
-                body.apply_force(
-                    0,
-                    &nphysics3d::math::Force::new(up * 20000.0, na::zero()),
-                    nphysics3d::algebra::ForceType::Force,
-                    true,
-                );
-            }
-            movement_direction_transformed *= 8.0;
+        }
+        if let Some(velocity) = movement.velocity {
             body.apply_force(
                 0,
-                &nphysics3d::math::Force::new(
-                    movement_direction_transformed - player_velocity,
-                    na::zero(),
-                ),
+                &nphysics3d::math::Force::new(velocity - player_velocity, na::zero()),
                 nphysics3d::algebra::ForceType::VelocityChange,
                 true,
             );
@@ -116,11 +95,14 @@ pub fn spawn(
     physics: &mut Physics,
     name: String,
     entity_id: shared::EntityId,
+    validation: crate::base::validation::Validation,
 ) -> hecs::Entity {
+    let spawn_point = na::Vector3::new(996609.65806255, -747775.7217986964, 414785.79067247955);
     let mut player = hecs::EntityBuilder::new();
+    // Interest management relies on transform, so it has to be valid before the first physics step
     player.add(Transform {
         isometry: na::Isometry3::from_parts(
-            na::Translation3::new(0.0, 0.0, 0.0),
+            na::Translation3::from(spawn_point),
             na::UnitQuaternion::from_euler_angles(0.0, 0.0, 3.14),
         ),
         ..Default::default()
@@ -135,11 +117,7 @@ pub fn spawn(
         RigidBodyDesc::new()
             //.collider(&ColliderDesc::new())
             .mass(40.0)
-            .translation(na::Vector3::new(
-                996609.65806255,
-                -747775.7217986964,
-                414785.79067247955,
-            ))
+            .translation(spawn_point)
             .kinematic_rotations(na::Vector3::new(true, true, true))
             .build(),
         &mut player,
@@ -160,7 +138,7 @@ pub fn spawn(
         ))
         .build(BodyPartHandle(player_body, 0)),
     );
-    player.add(Player::new(name, ground_sensor_handle));
+    player.add(Player::new(name, ground_sensor_handle, validation));
 
     let player_entity = world.spawn(player.build());
     physics.register_entity(player_body, player_entity.clone());
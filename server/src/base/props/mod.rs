@@ -33,7 +33,14 @@ impl GameManager {
         use ncollide3d::shape::*;
         use shared::components::{Drawable, Transform};
 
-        let prop_data = self.props[&prop_id].clone();
+        // Player commands are validated before they get here, other callers may not be
+        let prop_data = match self.props.get(&prop_id) {
+            Some(prop_data) => prop_data.clone(),
+            None => {
+                println!("[SERVER] Can't spawn unknown prop {}", prop_id);
+                return;
+            }
+        };
 
         let player_position = self
             .physics
@@ -117,12 +124,23 @@ impl GameManager {
         self.physics.register_entity(prop_body, entity);
         self.spawn(entity);
     }
-    pub fn load_props(&mut self) {
-        use std::path::Path;
-        let plate = load_prop_data(Path::new("./assets/props/plate_1x1.json")).unwrap();
-        let wheel = load_prop_data(Path::new("./assets/props/wheel.json")).unwrap();
-        self.props.insert(0, plate);
-        self.props.insert(1, wheel);
+    // Prop ids are indices in file name order, so they stay the same between restarts
+    pub fn load_props(&mut self, directory: &std::path::Path) -> Result<(), anyhow::Error> {
+        use anyhow::Context;
+        let mut paths = std::fs::read_dir(directory)
+            .with_context(|| format!("Can't read props directory {}", directory.display()))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.retain(|path| path.extension().map_or(false, |ext| ext == "json"));
+        paths.sort();
+        for (id, path) in paths.iter().enumerate() {
+            let prop = load_prop_data(path)
+                .map_err(|e| anyhow::anyhow!("{}", e))
+                .with_context(|| format!("Can't load prop {}", path.display()))?;
+            println!("[SERVER] Prop {}: {}", id, path.display());
+            self.props.insert(id, prop);
+        }
+        Ok(())
     }
 }
 
@@ -3,7 +3,9 @@ use crate::base::game_manager::GameManager;
 use crate::base::player::Player;
 use ncollide3d::query::{Ray, RayCast};
 use nphysics3d::object::{Body, BodyPartHandle, RigidBody};
+use shared::commands::Weld;
 use shared::components::Transform;
+use shared::EntityId;
 
 pub struct PickAble {
     pub owner: Option<hecs::Entity>,
@@ -126,6 +128,18 @@ impl GameManager {
                     if self.world.get::<PickAble>(*other_entity).is_err() {
                         continue;
                     }
+                    let (picked_id, other_id) = match (
+                        self.world.get::<EntityId>(picked_object),
+                        self.world.get::<EntityId>(*other_entity),
+                    ) {
+                        (Ok(picked_id), Ok(other_id)) => (*picked_id, *other_id),
+                        _ => continue,
+                    };
+                    // Props keep touching while they're welded, and either of them may be the picked one
+                    let ends = (picked_id.min(other_id), picked_id.max(other_id));
+                    if self.welds.iter().any(|weld| weld.ends() == ends) {
+                        continue;
+                    }
                     let other_transform = self.world.get::<Transform>(*other_entity).unwrap();
 
                     let middle_vec = picked_transform
@@ -151,6 +165,22 @@ impl GameManager {
                         anchor_2.rotation,
                     );
                     self.physics.joint_constraints.insert(constraint);
+                    let weld = if picked_id < other_id {
+                        Weld {
+                            first: picked_id,
+                            second: other_id,
+                            first_anchor: anchor_1,
+                            second_anchor: anchor_2,
+                        }
+                    } else {
+                        Weld {
+                            first: other_id,
+                            second: picked_id,
+                            first_anchor: anchor_2,
+                            second_anchor: anchor_1,
+                        }
+                    };
+                    self.welds.push(weld);
                 }
             }
         }
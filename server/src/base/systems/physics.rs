@@ -23,15 +23,18 @@ pub struct Physics {
     pub bodies: DefaultBodySet<f64>,
     pub entities: HashMap<DefaultBodyHandle, hecs::Entity>,
     pub planet_handle: DefaultBodyHandle,
+    pub planet_radius: f64,
+    // Shared with the planet collider
+    pub planet: std::sync::Arc<crate::planet::Planet>,
 }
 
 impl Physics {
-    pub fn new() -> Self {
-        // NOTE: At the current state of the development, this hardcode is fine.
-        let planet = crate::planet::Planet {
-            procgen: shared::planet::procgen::PlanetProcGen::default(),
-            radius: 1275620.0,
-        };
+    pub fn new(
+        config: &crate::config::Config,
+        procgen: shared::planet::procgen::PlanetProcGen,
+    ) -> Self {
+        let radius = config.planet.radius;
+        let planet = std::sync::Arc::new(crate::planet::Planet::new(procgen, radius));
 
         let mut mechanical_world = DefaultMechanicalWorld::new(na::zero());
         let geometrical_world = DefaultGeometricalWorld::from_parts(
@@ -52,20 +55,21 @@ impl Physics {
         colliders.insert(
             ColliderDesc::new(ShapeHandle::new(
                 crate::physics::collision::PlanetCollision::new(
-                    std::sync::Arc::new(planet),
+                    planet.clone(),
                     8,
-                    1275620.0,
-                    64 * 1024,
+                    radius,
+                    config.collision_cache_size,
                 ),
             ))
             .set_material(MaterialHandle::new(BasicMaterial::new(0.0, 2.0)))
             .build(BodyPartHandle(planet_handle, 0)),
         );
 
-        let gravity_well = crate::physics::PlanetGravity::new(3.0 * 10e22, na::Point3::origin());
+        let gravity_well =
+            crate::physics::PlanetGravity::new(config.planet.mass, na::Point3::origin());
         force_generators.insert(Box::new(gravity_well));
 
-        mechanical_world.set_timestep(1.0 / 60.0);
+        mechanical_world.set_timestep(1.0 / config.tickrate as f64);
         Self {
             mechanical_world,
             geometrical_world,
@@ -75,6 +79,8 @@ impl Physics {
             bodies,
             entities: HashMap::new(),
             planet_handle,
+            planet,
+            planet_radius: radius,
         }
     }
     pub fn add_body(
@@ -90,6 +96,34 @@ impl Physics {
     pub fn register_entity(&mut self, handle: DefaultBodyHandle, entity: hecs::Entity) {
         self.entities.insert(handle, entity);
     }
+    // Removes body along with everything attached to it
+    pub fn remove_body(&mut self, handle: DefaultBodyHandle) {
+        use nphysics3d::joint::JointConstraint;
+
+        let colliders: Vec<_> = self
+            .colliders
+            .iter()
+            .filter(|(_, collider)| collider.body() == handle)
+            .map(|(collider_handle, _)| collider_handle)
+            .collect();
+        for collider in colliders {
+            self.colliders.remove(collider);
+        }
+        let joints: Vec<_> = self
+            .joint_constraints
+            .iter()
+            .filter(|(_, joint)| {
+                let (a, b) = joint.anchors();
+                a.0 == handle || b.0 == handle
+            })
+            .map(|(joint_handle, _)| joint_handle)
+            .collect();
+        for joint in joints {
+            self.joint_constraints.remove(joint);
+        }
+        self.bodies.remove(handle);
+        self.entities.remove(&handle);
+    }
     pub fn run(&mut self, world: &mut hecs::World) {
         use shared::components::Transform;
 
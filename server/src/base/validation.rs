@@ -0,0 +1,238 @@
+// Clients can send anything. Commands are checked and fixed up before they reach the simulation,
+// everything that had to be changed is reported as a violation
+
+use crate::base::props::PropData;
+use crate::config::ValidationConfig;
+use shared::commands::ClientCommand;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Violation {
+    UnknownProp(u8),
+    SpawnRateExceeded,
+    PickupRateExceeded,
+    FlyNotAllowed,
+    // Not a rotation at all, e.g. NaNs or zero length
+    InvalidOrientation,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Violation::UnknownProp(id) => write!(f, "tried to spawn unknown prop {}", id),
+            Violation::SpawnRateExceeded => write!(f, "spawns props too fast"),
+            Violation::PickupRateExceeded => write!(f, "picks up props too fast"),
+            Violation::FlyNotAllowed => write!(f, "tried to fly without permission"),
+            Violation::InvalidOrientation => write!(f, "sent invalid orientation"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Permissions {
+    pub fly: bool,
+}
+
+// Token bucket, refilled every tick
+#[derive(Clone)]
+struct RateLimit {
+    tokens: f64,
+    burst: f64,
+    per_tick: f64,
+}
+
+impl RateLimit {
+    fn new(per_second: f64, burst: f64, tickrate: u8) -> Self {
+        Self {
+            tokens: burst,
+            burst,
+            per_tick: per_second / tickrate as f64,
+        }
+    }
+    fn refill(&mut self) {
+        self.tokens = (self.tokens + self.per_tick).min(self.burst);
+    }
+    fn take(&mut self) -> bool {
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Validation {
+    pub permissions: Permissions,
+    spawns: RateLimit,
+    pickups: RateLimit,
+    // Used in place of invalid ones
+    last_orientation: na::UnitQuaternion<f64>,
+    // Client keeps asking for fly every tick, it's only reported once
+    fly_denied: bool,
+}
+
+impl Validation {
+    pub fn new(config: &ValidationConfig, tickrate: u8) -> Self {
+        Self {
+            permissions: Permissions {
+                fly: config.allow_fly,
+            },
+            spawns: RateLimit::new(
+                config.prop_spawns_per_second,
+                config.prop_spawn_burst,
+                tickrate,
+            ),
+            pickups: RateLimit::new(config.pickups_per_second, config.pickup_burst, tickrate),
+            last_orientation: na::UnitQuaternion::identity(),
+            fly_denied: false,
+        }
+    }
+    // Called once a tick with the input for that tick
+    pub fn check(
+        &mut self,
+        command: &mut ClientCommand,
+        props: &HashMap<usize, PropData>,
+    ) -> Vec<Violation> {
+        self.spawns.refill();
+        self.pickups.refill();
+        let mut violations = vec![];
+
+        let orientation = command.orientation.into_inner();
+        if orientation.coords.iter().all(|x| x.is_finite()) && orientation.norm() > 1.0e-6 {
+            // Deserialized quaternion is not guaranteed to be unit
+            command.orientation = na::UnitQuaternion::new_normalize(orientation);
+            self.last_orientation = command.orientation;
+        } else {
+            command.orientation = self.last_orientation;
+            violations.push(Violation::InvalidOrientation);
+        }
+
+        if command.fly && !self.permissions.fly {
+            command.fly = false;
+            if !self.fly_denied {
+                violations.push(Violation::FlyNotAllowed);
+            }
+            self.fly_denied = true;
+        } else {
+            self.fly_denied = false;
+        }
+
+        if let Some(prop) = command.prop_spawn {
+            if !props.contains_key(&(prop as usize)) {
+                command.prop_spawn = None;
+                violations.push(Violation::UnknownProp(prop));
+            } else if !self.spawns.take() {
+                command.prop_spawn = None;
+                violations.push(Violation::SpawnRateExceeded);
+            }
+        }
+
+        if command.pickup && !self.pickups.take() {
+            command.pickup = false;
+            violations.push(Violation::PickupRateExceeded);
+        }
+        violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command() -> ClientCommand {
+        ClientCommand {
+            sequence: 0,
+            tick_ack: 0,
+            movement_direction: na::Vector2::new(0, 0),
+            orientation: na::UnitQuaternion::identity(),
+            fly: false,
+            jump: false,
+            run: false,
+            sit: false,
+            pickup: false,
+            prop_spawn: None,
+        }
+    }
+
+    fn props() -> HashMap<usize, PropData> {
+        let prop = serde_json::from_str(
+            r#"{ "model": "cube.gltf", "collider_desc": { "shape": { "Ball": 1.0 } } }"#,
+        )
+        .unwrap();
+        let mut props = HashMap::new();
+        props.insert(0, prop);
+        props
+    }
+
+    #[test]
+    fn unknown_prop_is_rejected() {
+        let mut validation = Validation::new(&ValidationConfig::default(), 60);
+        let mut spawn = command();
+        spawn.prop_spawn = Some(7);
+        let violations = validation.check(&mut spawn, &props());
+        assert_eq!(violations, vec![Violation::UnknownProp(7)]);
+        assert_eq!(spawn.prop_spawn, None);
+    }
+
+    #[test]
+    fn spawns_are_rate_limited() {
+        let config = ValidationConfig {
+            prop_spawns_per_second: 1.0,
+            prop_spawn_burst: 2.0,
+            ..Default::default()
+        };
+        let mut validation = Validation::new(&config, 10);
+        let props = props();
+        let mut spawned = 0;
+        // 5 seconds of spawning every tick
+        for _ in 0..50 {
+            let mut spawn = command();
+            spawn.prop_spawn = Some(0);
+            validation.check(&mut spawn, &props);
+            if spawn.prop_spawn.is_some() {
+                spawned += 1;
+            }
+        }
+        // Burst plus one a second after that
+        assert!((6..=7).contains(&spawned), "spawned {}", spawned);
+    }
+
+    #[test]
+    fn fly_needs_permission() {
+        let config = ValidationConfig {
+            allow_fly: false,
+            ..Default::default()
+        };
+        let mut validation = Validation::new(&config, 60);
+        let mut fly = command();
+        fly.fly = true;
+        let mut first = fly;
+        assert_eq!(
+            validation.check(&mut first, &props()),
+            vec![Violation::FlyNotAllowed]
+        );
+        // Reported once
+        assert!(validation.check(&mut fly, &props()).is_empty());
+        assert!(!fly.fly);
+    }
+
+    #[test]
+    fn orientation_is_normalized() {
+        let mut validation = Validation::new(&ValidationConfig::default(), 60);
+        let mut scaled = command();
+        scaled.orientation = na::Unit::new_unchecked(na::Quaternion::new(0.0, 0.0, 0.0, 3.0));
+        assert!(validation.check(&mut scaled, &props()).is_empty());
+        assert!((scaled.orientation.norm() - 1.0).abs() < 1.0e-9);
+
+        let mut broken = command();
+        broken.orientation =
+            na::Unit::new_unchecked(na::Quaternion::new(std::f64::NAN, 0.0, 0.0, 0.0));
+        assert_eq!(
+            validation.check(&mut broken, &props()),
+            vec![Violation::InvalidOrientation]
+        );
+        assert_eq!(broken.orientation, scaled.orientation);
+    }
+}
@@ -0,0 +1,113 @@
+// Server identity. Clients remember the certificate fingerprint, so it has to survive restarts:
+// certificate and key are generated once and loaded from disk after that
+
+use anyhow::{anyhow, Context, Error};
+use quinn::{Certificate, CertificateChain, PrivateKey};
+use std::path::Path;
+
+pub fn load_or_generate(
+    certificate_path: &Path,
+    key_path: &Path,
+) -> Result<(CertificateChain, PrivateKey), Error> {
+    let (certificates, key) = match (certificate_path.exists(), key_path.exists()) {
+        (true, true) => (read_certificates(certificate_path)?, read_key(key_path)?),
+        (false, false) => generate(certificate_path, key_path)?,
+        // Don't replace half of the identity, fingerprint would change anyway
+        _ => {
+            return Err(anyhow!(
+                "Only one of {} and {} exists, provide both or remove the other one",
+                certificate_path.display(),
+                key_path.display()
+            ))
+        }
+    };
+    println!(
+        "[SERVER] Certificate fingerprint: {}",
+        shared::network::fingerprint(&certificates[0])
+    );
+    let chain = certificates
+        .iter()
+        .map(|der| Certificate::from_der(der))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid certificate in {}", certificate_path.display()))?;
+    let key = PrivateKey::from_der(&key)
+        .with_context(|| format!("Invalid private key in {}", key_path.display()))?;
+    Ok((CertificateChain::from_certs(chain), key))
+}
+
+fn generate(certificate_path: &Path, key_path: &Path) -> Result<(Vec<Vec<u8>>, Vec<u8>), Error> {
+    println!("[SERVER] Generating certificate...");
+    let cert = rcgen::generate_simple_self_signed(vec!["recyclers-server".to_string()])?;
+    let key = cert.serialize_private_key_der();
+    let der = cert.serialize_der()?;
+    for path in &[certificate_path, key_path] {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+    }
+    std::fs::write(certificate_path, cert.serialize_pem()?)
+        .with_context(|| format!("Can't save certificate to {}", certificate_path.display()))?;
+    write_private(key_path, cert.serialize_private_key_pem().as_bytes())
+        .with_context(|| format!("Can't save private key to {}", key_path.display()))?;
+    println!(
+        "[SERVER] Saved certificate to {} and key to {}",
+        certificate_path.display(),
+        key_path.display()
+    );
+    Ok((vec![der], key))
+}
+
+// Both PEM and DER are accepted. PEM may contain the whole chain, leaf certificate goes first
+fn read_certificates(path: &Path) -> Result<Vec<Vec<u8>>, Error> {
+    let data = std::fs::read(path)
+        .with_context(|| format!("Can't read certificate {}", path.display()))?;
+    if !is_pem(&data) {
+        return Ok(vec![data]);
+    }
+    let certificates: Vec<_> = pem::parse_many(&data)
+        .into_iter()
+        .filter(|pem| pem.tag == "CERTIFICATE")
+        .map(|pem| pem.contents)
+        .collect();
+    if certificates.is_empty() {
+        return Err(anyhow!("No certificates in {}", path.display()));
+    }
+    Ok(certificates)
+}
+
+fn read_key(path: &Path) -> Result<Vec<u8>, Error> {
+    let data = std::fs::read(path)
+        .with_context(|| format!("Can't read private key {}", path.display()))?;
+    if !is_pem(&data) {
+        return Ok(data);
+    }
+    pem::parse_many(&data)
+        .into_iter()
+        .find(|pem| pem.tag.ends_with("PRIVATE KEY"))
+        .map(|pem| pem.contents)
+        .ok_or_else(|| anyhow!("No private key in {}", path.display()))
+}
+
+fn is_pem(data: &[u8]) -> bool {
+    String::from_utf8_lossy(data)
+        .trim_start()
+        .starts_with("-----BEGIN")
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(data)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    std::fs::write(path, data)
+}
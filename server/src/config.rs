@@ -0,0 +1,188 @@
+use anyhow::{anyhow, Context, Error};
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_PATH: &str = "./server.json";
+
+const USAGE: &str = "Options:
+    --config <path>             Config file, ./server.json by default
+    --bind <address:port>       Address to listen on
+    --tickrate <ticks>          Simulation ticks per second
+    --max-players <count>       Connections over that are rejected
+    --session-grace <secs>      How long players who lost connection wait for their client to come back
+    --planet <path>             Planet layers definition
+    --planet-radius <meters>
+    --planet-mass <kg>
+    --planet-seed <seed>
+    --props <path>              Directory with prop definitions
+    --collision-cache <chunks>  Terrain collision chunks kept in memory
+    --certificate <path>        Certificate chain, PEM or DER. Generated if missing
+    --key <path>                Private key, PEM or DER. Generated if missing
+    --allow-fly <true|false>    Whether players may fly
+    --simulate-latency <ms>     Delay everything clients send by that much
+    --simulate-jitter <ms>      Random extra delay up to that
+    --simulate-loss <0-1>       Chance of a datagram from a client being lost
+    --simulate-reorder <0-1>    Chance of a datagram from a client arriving late
+    --simulate-bandwidth <B/s>  Cap on what each client can send
+    --simulate-queue <ms>       Datagrams that would wait longer than that for bandwidth are dropped
+    --simulate-seed <seed>      Same seed gives the same drops and delays";
+
+// Everything is optional in the file, missing fields are taken from Default
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub bind_address: String,
+    // 255 ticks/s is probably more than enough. 90% of the servers will use 60, maybe 128, but not more
+    pub tickrate: u8,
+    pub max_players: usize,
+    // Seconds. Player whose connection was lost stays in the world that long, see session.rs.
+    // Zero despawns it right away
+    pub session_grace: u64,
+    pub planet: PlanetConfig,
+    // Every .json file in there is a prop. Ids are assigned in file name order
+    pub props_directory: String,
+    pub collision_cache_size: usize,
+    pub certificate: String,
+    pub key: String,
+    pub validation: ValidationConfig,
+    // Bad network for testing, applied to what server receives. See shared::transport::simulated
+    pub simulate: shared::transport::simulated::Conditions,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlanetConfig {
+    pub layers: String,
+    pub radius: f64,
+    // Only used for gravity
+    pub mass: f64,
+    pub seed: u16,
+}
+
+// Limits on what clients may do, see base::validation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ValidationConfig {
+    pub allow_fly: bool,
+    // Rate limits. Sustained rate and how many can be done at once after a pause
+    pub prop_spawns_per_second: f64,
+    pub prop_spawn_burst: f64,
+    pub pickups_per_second: f64,
+    pub pickup_burst: f64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind_address: "0.0.0.0:1234".to_string(),
+            tickrate: 60,
+            max_players: 64,
+            session_grace: 30,
+            planet: PlanetConfig::default(),
+            props_directory: "./assets/props".to_string(),
+            collision_cache_size: 64 * 1024,
+            certificate: "./server_cert.pem".to_string(),
+            key: "./server_key.pem".to_string(),
+            validation: ValidationConfig::default(),
+            simulate: Default::default(),
+        }
+    }
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        Self {
+            allow_fly: true,
+            prop_spawns_per_second: 2.0,
+            prop_spawn_burst: 5.0,
+            pickups_per_second: 4.0,
+            pickup_burst: 4.0,
+        }
+    }
+}
+
+impl Default for PlanetConfig {
+    fn default() -> Self {
+        Self {
+            layers: "./assets/planet.json".to_string(),
+            radius: 1275620.0,
+            mass: 3.0 * 10e22,
+            seed: 1234,
+        }
+    }
+}
+
+impl Config {
+    // Reads config file and applies command line overrides on top of it
+    pub fn from_args() -> Result<Self, Error> {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        let mut overrides = vec![];
+        let mut path = None;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let value = match args.next() {
+                Some(value) => value.clone(),
+                None => return Err(anyhow!("Missing value for {}\n{}", arg, USAGE)),
+            };
+            if arg == "--config" {
+                path = Some(value);
+            } else {
+                overrides.push((arg.clone(), value));
+            }
+        }
+        let mut config = match path {
+            Some(path) => Self::load(std::path::Path::new(&path))?,
+            None => {
+                let path = std::path::Path::new(DEFAULT_PATH);
+                if path.exists() {
+                    Self::load(path)?
+                } else {
+                    Self::default()
+                }
+            }
+        };
+        for (arg, value) in overrides {
+            config
+                .set(&arg, &value)
+                .with_context(|| format!("Invalid value for {}: {}", arg, value))?;
+        }
+        if config.tickrate == 0 {
+            return Err(anyhow!("Tickrate can't be zero"));
+        }
+        config.simulate.check().map_err(|e| anyhow!(e))?;
+        Ok(config)
+    }
+    pub fn load(path: &std::path::Path) -> Result<Self, Error> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Can't open config {}", path.display()))?;
+        let reader = std::io::BufReader::new(file);
+        let config = serde_json::from_reader(reader)
+            .with_context(|| format!("Can't parse config {}", path.display()))?;
+        Ok(config)
+    }
+    fn set(&mut self, arg: &str, value: &str) -> Result<(), Error> {
+        match arg {
+            "--bind" => self.bind_address = value.to_string(),
+            "--tickrate" => self.tickrate = value.parse()?,
+            "--max-players" => self.max_players = value.parse()?,
+            "--session-grace" => self.session_grace = value.parse()?,
+            "--planet" => self.planet.layers = value.to_string(),
+            "--planet-radius" => self.planet.radius = value.parse()?,
+            "--planet-mass" => self.planet.mass = value.parse()?,
+            "--planet-seed" => self.planet.seed = value.parse()?,
+            "--props" => self.props_directory = value.to_string(),
+            "--collision-cache" => self.collision_cache_size = value.parse()?,
+            "--certificate" => self.certificate = value.to_string(),
+            "--key" => self.key = value.to_string(),
+            "--allow-fly" => self.validation.allow_fly = value.parse()?,
+            "--simulate-latency" => self.simulate.latency = value.parse()?,
+            "--simulate-jitter" => self.simulate.jitter = value.parse()?,
+            "--simulate-loss" => self.simulate.loss = value.parse()?,
+            "--simulate-reorder" => self.simulate.reorder = value.parse()?,
+            "--simulate-bandwidth" => self.simulate.bandwidth = Some(value.parse()?),
+            "--simulate-queue" => self.simulate.queue = value.parse()?,
+            "--simulate-seed" => self.simulate.seed = value.parse()?,
+            _ => return Err(anyhow!("Unknown option\n{}", USAGE)),
+        }
+        Ok(())
+    }
+}
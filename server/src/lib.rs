@@ -1,206 +1,690 @@
 extern crate nalgebra as na;
 
 pub mod base;
+pub mod certificate;
+pub mod config;
+pub mod outbound;
 pub mod physics;
 pub mod planet;
+pub mod session;
 
-use anyhow::Error;
-use futures::{select, StreamExt, TryStreamExt};
-use quinn::{Certificate, CertificateChain, PrivateKey};
-use shared::commands::ClientCommand;
+use anyhow::{anyhow, Context, Error};
+use base::interest::Interest;
+use futures::stream::BoxStream;
+use futures::{select, StreamExt};
+use outbound::{Outbound, OutboundError, OutboundReceiver};
+use session::Sessions;
+use shared::codec::{DeltaFilter, TransformCodec};
+use shared::commands::{
+    ClientCommand, ClientInfo, ClientVersion, HandshakeResponse, PositionUpdate, ServerMessage,
+    BUILD_HASH, PROTOCOL_VERSION,
+};
+use shared::network::{Message, NetworkError};
+use shared::transport::metered::{self, Rates, Traffic};
+use shared::transport::{simulated, Connection, Incoming, NewConnection, RecvStream};
 use slotmap::new_key_type;
 use slotmap::DenseSlotMap;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use tokio::sync::mpsc;
 
 //TODO: I might need some kind of server_println!() macro or smthng
 
 //TODO: There are a lot of .unwrap()s, it's better to get rid of them
 
-type Ordered = shared::commands::Tick;
-
 new_key_type! {
     pub struct ClientId;
 }
 
+// Client that got through the handshake, waiting to get into the game
+struct Handshake {
+    conn: NewConnection,
+    traffic: Arc<Traffic>,
+    info: ClientInfo,
+}
+
+// Client gets that long to send its info, a slow one is only holding up itself
+const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+enum ClientEvent {
+    Command(ClientCommand),
+    // Lost ones may come back, see session.rs
+    Disconnected { lost: bool },
+}
+
 struct Client {
-    conn: quinn::Connection,
-    ordered: mpsc::Sender<Ordered>,
+    conn: Arc<dyn Connection>,
+    name: String,
+    ordered: Outbound,
     entity: hecs::Entity,
+    interest: Interest,
+    // Dropped ticks at the time of the last lag report
+    reported_drops: usize,
+    // Last command sequence number client was told about
+    sent_ack: u32,
+    traffic: Arc<Traffic>,
+    // Updated once a second
+    rates: Rates,
+    // Newest tick client has confirmed receiving
+    acked_tick: u64,
+    // Token this connection got in ServerInfo
+    session: u64,
+    // Every violation is counted, but only one a second gets logged
+    violations: u64,
+    logged_violation_at: Option<u64>,
 }
 
 pub struct Server {
     clients: DenseSlotMap<ClientId, Client>,
     game: crate::base::game_manager::GameManager,
-    tickrate: u8, // 255 ticks/s is probably more than enough. 90% of the servers will use 60, maybe 128, but not more
+    current_tick: u64,
+    codec: TransformCodec,
+    delta: DeltaFilter,
+    config: config::Config,
+    // Every connection gets its own simulator seed
+    connections: u64,
+    // Players waiting for their client to reconnect
+    sessions: Sessions,
 }
 
 impl Server {
-    async fn run(mut self, incoming: quinn::Incoming) {
+    pub fn new(config: config::Config) -> Result<Self, Error> {
+        let game = crate::base::game_manager::GameManager::new(&config)?;
+        let codec = TransformCodec::new(game.physics.planet_radius);
+        Ok(Server {
+            clients: DenseSlotMap::default(),
+            codec,
+            delta: DeltaFilter::default(),
+            game: game,
+            current_tick: 0,
+            config,
+            connections: 0,
+            sessions: Sessions::default(),
+        })
+    }
+
+    // Runs until the process exits, whatever transport incoming connections come from.
+    // Admin commands are lines of text, see on_admin
+    pub async fn run(mut self, incoming: Incoming, admin: BoxStream<'static, String>) {
         let mut ticks =
-            tokio::time::interval(std::time::Duration::from_secs(1) / self.tickrate as u32).fuse();
+            tokio::time::interval(std::time::Duration::from_secs(1) / self.config.tickrate as u32)
+                .fuse();
         let mut incoming = incoming
             .inspect(|_conn| println!("[SERVER] Client is trying to connect to the server"))
             .buffer_unordered(16);
         let (events_tx, events_rx) = mpsc::channel(128);
         let mut events_rx = events_rx.fuse();
+        let (handshakes_tx, handshakes_rx) = mpsc::channel(16);
+        let mut handshakes_rx = handshakes_rx.fuse();
+        let mut admin = admin.fuse();
+        if self.config.simulate.is_active() {
+            println!(
+                "[SERVER] Simulating network conditions: {:?}",
+                self.config.simulate
+            );
+        }
         loop {
             select! {
                 _ = ticks.next() => {
-                    self.tick().await;
+                    self.tick();
                 },
                 conn = incoming.select_next_some() => {
-                    self.on_connect(conn, events_tx.clone()).await;
+                    self.on_connect(conn, handshakes_tx.clone());
+                },
+                handshake = handshakes_rx.select_next_some() => {
+                    self.on_handshake(handshake, events_tx.clone());
                 },
                 e = events_rx.select_next_some() => {
                     self.on_event(e.0, e.1);
+                },
+                command = admin.select_next_some() => {
+                    self.on_admin(&command);
                 }
             };
         }
     }
 
-    async fn tick(&mut self) {
-        let (spawns, positions) = self.game.step();
+    fn tick(&mut self) {
+        for entity in self.sessions.expire(self.current_tick) {
+            println!("[SERVER] Player didn't reconnect in time, despawning it");
+            self.game.despawn_player(entity);
+        }
+        let (despawns, positions) = self.game.step();
+        self.current_tick += 1;
+        for id in &despawns {
+            self.delta.forget(id);
+        }
+        let positions = self.delta.filter(self.current_tick, &self.codec, positions);
+        let (tick, tickrate) = (self.current_tick, self.config.tickrate as u64);
+        for (entity, violation) in self.game.drain_violations() {
+            if let Some((client_id, client)) = self
+                .clients
+                .iter_mut()
+                .find(|(_, client)| client.entity == entity)
+            {
+                client.violations += 1;
+                let logged_recently = client
+                    .logged_violation_at
+                    .map_or(false, |logged| tick - logged < tickrate);
+                if !logged_recently {
+                    println!(
+                        "[SERVER] Client {:?} {} ({} violations so far)",
+                        client_id, violation, client.violations
+                    );
+                    client.logged_violation_at = Some(tick);
+                }
+            }
+        }
+        let report_lag = self.current_tick % self.config.tickrate as u64 == 0;
+        // Checked once a second, same as lag
+        let layers = if report_lag {
+            self.game.physics.planet.try_reload()
+        } else {
+            None
+        };
+        if layers.is_some() {
+            println!("[SERVER] Planet layers reloaded");
+        }
+        // Lost ones can come back and get a fresh snapshot
+        let mut disconnected = vec![];
         // Send tick info to each client
-        for (_client_id, client) in &mut self.clients {
-            client
-                .ordered
-                .send(shared::commands::Tick {
-                    spawns: spawns.clone(),
-                    positions: positions.clone(),
-                })
-                .await
-                .unwrap();
-        }
-    }
-
-    fn on_event(&self, client_id: ClientId, event: ClientCommand) {
-        // TODO: Move to GameManager
-        let player = self.clients[client_id].entity;
-        let mut player = self
-            .game
-            .world
-            .get_mut::<crate::base::player::Player>(player)
-            .unwrap();
-        player.state = Some(event);
-    }
-
-    async fn on_connect(
+        for (client_id, client) in &mut self.clients {
+            let (spawns, despawns) = self
+                .game
+                .update_interest(client.entity, &mut client.interest);
+            let (welds, unwelds) = self.game.update_welds(&mut client.interest);
+            let mut result = Ok(());
+            if let Some(layers) = &layers {
+                result = client
+                    .ordered
+                    .push_reliable(ServerMessage::Layers(layers.clone()));
+            }
+            let changed = !spawns.is_empty()
+                || !despawns.is_empty()
+                || !welds.is_empty()
+                || !unwelds.is_empty();
+            if result.is_ok() && changed {
+                let events = shared::commands::Tick {
+                    tick: self.current_tick,
+                    spawns,
+                    despawns,
+                    welds,
+                    unwelds,
+                };
+                result = client.ordered.push_reliable(ServerMessage::Tick(events));
+            }
+            let visible: Vec<_> = positions
+                .iter()
+                .filter(|(id, _)| client.interest.contains(id))
+                .cloned()
+                .collect();
+            let input_ack = self
+                .game
+                .world
+                .get::<crate::base::player::Player>(client.entity)
+                .map(|player| player.last_sequence)
+                .unwrap_or(client.sent_ack);
+            // Client needs acks even if nothing moved, otherwise it can't drop confirmed inputs
+            if result.is_ok() && (!visible.is_empty() || input_ack != client.sent_ack) {
+                result = client.ordered.push_positions(PositionUpdate {
+                    tick: self.current_tick,
+                    input_ack,
+                    positions: visible,
+                });
+                client.sent_ack = input_ack;
+            }
+            match result {
+                Ok(()) => {}
+                // Sender task is gone, so is the connection
+                Err(OutboundError::Closed) => disconnected.push(client_id),
+                Err(OutboundError::Lagging) => {
+                    println!(
+                        "[SERVER] Client {:?} can't keep up with the server, dropping it",
+                        client_id
+                    );
+                    disconnected.push(client_id);
+                }
+            }
+            if report_lag {
+                client.rates.sample(&client.traffic);
+                let stats = client.ordered.stats();
+                let dropped = stats.dropped_ticks.load(Ordering::Relaxed);
+                if dropped > client.reported_drops {
+                    println!(
+                        "[SERVER] Client {:?} is lagging: {} messages queued, {} ticks dropped in total",
+                        client_id,
+                        stats.queued.load(Ordering::Relaxed),
+                        dropped
+                    );
+                    client.reported_drops = dropped;
+                }
+            }
+        }
+        for client_id in disconnected {
+            self.disconnect(client_id, true);
+        }
+    }
+
+    fn on_event(&mut self, client_id: ClientId, event: ClientEvent) {
+        match event {
+            ClientEvent::Command(command) => {
+                // Commands may still be in flight after client is gone
+                let client = match self.clients.get_mut(client_id) {
+                    Some(client) => client,
+                    None => return,
+                };
+                // Client can't have anything from the future
+                client.acked_tick = client
+                    .acked_tick
+                    .max(command.tick_ack.min(self.current_tick));
+                let player = client.entity;
+                // TODO: Move to GameManager
+                let mut player = self
+                    .game
+                    .world
+                    .get_mut::<crate::base::player::Player>(player)
+                    .unwrap();
+                player.input.push(command);
+            }
+            ClientEvent::Disconnected { lost } => self.disconnect(client_id, lost),
+        }
+    }
+
+    fn on_admin(&mut self, command: &str) {
+        match command.trim() {
+            "" => {}
+            "stats" => self.print_stats(),
+            command => println!("[SERVER] Unknown command {:?}, available: stats", command),
+        }
+    }
+
+    // Rates are for the last full second
+    fn print_stats(&self) {
+        println!(
+            "[SERVER] {} clients, {} waiting to reconnect, tick {}",
+            self.clients.len(),
+            self.sessions.count(),
+            self.current_tick
+        );
+        for (client_id, client) in &self.clients {
+            let rtt = client.conn.rtt().map_or("-".to_string(), |rtt| {
+                format!("{:.1}ms", rtt.as_secs_f64() * 1000.0)
+            });
+            let rates = &client.rates;
+            let stats = client.ordered.stats();
+            println!(
+                "[SERVER] Client {:?} {:?}: rtt {}, in {:.1} KiB/s {:.0} msg/s, out {:.1} KiB/s {:.0} msg/s, \
+                 backlog {} queued {} ticks dropped, acked tick {} ({} behind), {} violations",
+                client_id,
+                client.name,
+                rtt,
+                rates.bytes_in / 1024.0,
+                rates.messages_in,
+                rates.bytes_out / 1024.0,
+                rates.messages_out,
+                stats.queued.load(Ordering::Relaxed),
+                stats.dropped_ticks.load(Ordering::Relaxed),
+                client.acked_tick,
+                self.current_tick.saturating_sub(client.acked_tick),
+                client.violations,
+            );
+        }
+    }
+
+    // Player of a lost client is kept for a while, so it can reconnect to it
+    fn disconnect(&mut self, client_id: ClientId, lost: bool) {
+        let client = match self.clients.remove(client_id) {
+            Some(client) => client,
+            None => return,
+        };
+        client.conn.close(b"disconnected");
+        if lost && self.config.session_grace > 0 {
+            println!(
+                "[SERVER] Client {:?} has lost connection, keeping its player for {}s",
+                client_id, self.config.session_grace
+            );
+            self.game.detach_player(client.entity);
+            let grace = self.config.session_grace * self.config.tickrate as u64;
+            self.sessions
+                .detach(client.session, client.entity, self.current_tick + grace);
+        } else {
+            println!("[SERVER] Client {:?} has disconnected", client_id);
+            self.game.despawn_player(client.entity);
+        }
+    }
+
+    // Player the token belongs to, if it's still around. Old connection may not have noticed
+    // it's dead yet, the new one takes over
+    fn resume(&mut self, token: u64) -> Option<(shared::EntityId, hecs::Entity)> {
+        let stale = self
+            .clients
+            .iter()
+            .find(|(_, client)| client.session == token)
+            .map(|(client_id, _)| client_id);
+        if let Some(stale) = stale {
+            self.disconnect(stale, true);
+        }
+        let entity = self.sessions.resume(token)?;
+        let id = *self.game.world.get::<shared::EntityId>(entity).ok()?;
+        Some((id, entity))
+    }
+
+    // Handshake runs on its own, a client that goes quiet halfway through it must not hold up the ticks
+    fn on_connect(
         &mut self,
-        conn: Result<quinn::NewConnection, quinn::ConnectionError>,
-        mut events_tx: mpsc::Sender<(ClientId, ClientCommand)>,
+        conn: Result<NewConnection, NetworkError>,
+        mut handshakes_tx: mpsc::Sender<Handshake>,
     ) {
-        let mut conn = conn.unwrap();
-        let connection = conn.connection.clone();
-        let client_info = match conn.uni_streams.next().await {
-            None => {
+        let mut conn = match conn {
+            Ok(conn) => conn,
+            Err(e) => {
+                println!("[SERVER] Client failed to connect: {}", e);
                 return;
             }
-            Some(stream) => {
-                shared::network::receive::<shared::commands::ClientInfo>(&mut stream.unwrap())
-                    .await
-                    .unwrap()
-            }
         };
-        let (ordered_tx, mut ordered_rx) = mpsc::channel(128);
+        self.connections += 1;
+        if self.config.simulate.is_active() {
+            let seed = self.config.simulate.seed.wrapping_add(self.connections);
+            conn = simulated::wrap(conn, &self.config.simulate, seed);
+        }
+        let traffic = Arc::new(Traffic::default());
+        let mut conn = metered::wrap(conn, traffic.clone());
+        tokio::spawn(async move {
+            let connection = conn.connection.clone();
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, receive_client_info(&mut conn)).await {
+                Ok(Some(info)) => {
+                    let _ = handshakes_tx
+                        .send(Handshake {
+                            conn,
+                            traffic,
+                            info,
+                        })
+                        .await;
+                }
+                Ok(None) => {}
+                Err(_) => {
+                    println!("[SERVER] Client took too long to send its info");
+                    connection.close(b"handshake timeout");
+                }
+            }
+        });
+    }
 
-        // Take snapshot before spawning a player
-        let snapshot = self.game.snapshot();
-        let (eid, e) = self.game.spawn_player(client_info.clone());
+    fn on_handshake(
+        &mut self,
+        handshake: Handshake,
+        mut events_tx: mpsc::Sender<(ClientId, ClientEvent)>,
+    ) {
+        let Handshake {
+            conn,
+            traffic,
+            info: client_info,
+        } = handshake;
+        let connection = conn.connection.clone();
+        let resumed = client_info.session.and_then(|token| self.resume(token));
+        // Players waiting for their client still take up a slot
+        if resumed.is_none()
+            && self.clients.len() + self.sessions.count() >= self.config.max_players
+        {
+            reject(connection, "server is full".to_string());
+            return;
+        }
+        if client_info.build_hash != BUILD_HASH {
+            println!(
+                "[SERVER] Client is running build {}, server is running build {}",
+                client_info.build_hash, BUILD_HASH
+            );
+        }
+        let (ordered_tx, ordered_rx) = outbound::channel(connection.clone());
+
+        let (eid, e) = match resumed {
+            Some(player) => {
+                println!("[SERVER] Client {} is back", client_info.name);
+                player
+            }
+            None => self.game.spawn_player(client_info.clone()),
+        };
+        // Snapshot only contains what player can see, the rest comes with ticks
+        let mut interest = Interest::default();
+        let (entities, _) = self.game.update_interest(e, &mut interest);
+        let (welds, _) = self.game.update_welds(&mut interest);
+        let snapshot = shared::commands::Snapshot {
+            tick: self.current_tick,
+            entities,
+            welds,
+            last: true,
+        };
+        let session = Sessions::new_token();
         let id = self.clients.insert(Client {
             conn: connection.clone(),
+            name: client_info.name.clone(),
             entity: e,
+            interest,
             ordered: ordered_tx,
+            reported_drops: 0,
+            sent_ack: 0,
+            traffic,
+            rates: Rates::new(),
+            acked_tick: self.current_tick,
+            session,
+            violations: 0,
+            logged_violation_at: None,
         });
 
         let server_info = shared::commands::ServerInfo {
+            protocol_version: PROTOCOL_VERSION,
+            build_hash: BUILD_HASH.to_string(),
             character_id: eid.0,
-            tickrate: self.tickrate,
-            // TODO: Use actual values
-            planet_seed: 1234,
-            planet_radius: 720,
+            tickrate: self.config.tickrate,
+            planet_seed: self.config.planet.seed,
+            planet_radius: self.game.physics.planet_radius,
+            planet_layers: self.game.physics.planet.layers(),
+            session,
+            allow_fly: self.config.validation.allow_fly,
         };
         // Receiver thread
+        let receiver_connection = connection.clone();
         tokio::spawn(async move {
             println!("[SERVER] Client has connected to the server");
             println!("[SERVER] Client info {:?}", client_info);
-            let mut cmds = conn
-                .uni_streams
-                .map(|stream| async {
-                    Ok::<_, Error>(
-                        shared::network::receive::<shared::commands::ClientCommand>(
-                            &mut stream.unwrap(),
-                        )
-                        .await
-                        .unwrap(),
-                    )
-                })
-                .buffer_unordered(16);
-            loop {
-                //let msg = cmds.next().await.unwrap();
-                while let Some(msg) = cmds.try_next().await.unwrap() {
-                    events_tx.send((id, msg)).await.unwrap();
+            // Client that finished its command stream is leaving
+            let lost = match receive_commands(conn.uni_streams, id, &mut events_tx).await {
+                Ok(()) => false,
+                Err(e) => {
+                    println!("[SERVER] Client {:?} disconnected: {}", id, e);
+                    e.is_lost()
                 }
-            }
+            };
+            receiver_connection.close(b"disconnected");
+            let _ = events_tx
+                .send((id, ClientEvent::Disconnected { lost }))
+                .await;
         });
         tokio::spawn(async move {
-            let mut stream = connection.open_uni().await.unwrap();
-            println!("[SERVER] Sending server info...");
-            shared::network::send(&mut stream, &server_info).await;
-            // Intial tick. Used to send snapshot
-            shared::network::send(
-                &mut stream,
-                &shared::commands::Tick {
-                    spawns: snapshot,
-                    positions: vec![],
-                },
-            )
-            .await;
-
-            stream.finish().await.unwrap();
-
-            let mut stream = connection.open_uni().await.unwrap();
-            while let Some(command) = ordered_rx.recv().await {
-                shared::network::send(&mut stream, &command).await;
+            if let Err(e) = send_ordered(&*connection, server_info, snapshot, ordered_rx).await {
+                println!("[SERVER] Failed to send data to client {:?}: {}", id, e);
+                connection.close(b"disconnected");
             }
-            stream.finish().await.unwrap();
         });
     }
 }
 
-pub fn generate_certificate() -> (CertificateChain, PrivateKey) {
-    println!("[SERVER] Generating certificate...");
-    let cert = rcgen::generate_simple_self_signed(vec!["recyclers-server".to_string()]).unwrap();
-    let key = cert.serialize_private_key_der();
-    let cert = cert.serialize_der().unwrap();
-    (
-        CertificateChain::from_certs(Certificate::from_der(&cert)),
-        PrivateKey::from_der(&key).unwrap(),
+// Version first, then the rest of client info. None if the client was turned away
+async fn receive_client_info(conn: &mut NewConnection) -> Option<ClientInfo> {
+    let connection = conn.connection.clone();
+    let frame = match shared::network::accept_uni(&mut conn.uni_streams).await {
+        Err(NetworkError::Closed) => {
+            return None;
+        }
+        Err(e) => {
+            println!("[SERVER] Client disconnected during handshake: {}", e);
+            return None;
+        }
+        Ok(mut stream) => {
+            match shared::network::receive_frame::<ClientInfo, _>(&mut stream).await {
+                Ok(frame) => frame,
+                Err(e) => {
+                    println!("[SERVER] Failed to receive client info: {}", e);
+                    connection.close(b"bad handshake");
+                    return None;
+                }
+            }
+        }
+    };
+    // Version first, the rest of client info may be laid out differently in other builds
+    let version = match shared::network::decode_prefix::<ClientVersion>(&frame) {
+        Ok(version) => version,
+        Err(e) => {
+            println!("[SERVER] Failed to receive client info: {}", e);
+            connection.close(b"bad handshake");
+            return None;
+        }
+    };
+    if version.protocol_version != PROTOCOL_VERSION {
+        let reason = format!(
+            "protocol version mismatch: server is {} (build {}), client is {} (build {})",
+            PROTOCOL_VERSION, BUILD_HASH, version.protocol_version, version.build_hash
+        );
+        reject(connection, reason);
+        return None;
+    }
+    match shared::network::decode::<ClientInfo>(&frame) {
+        Ok(client_info) => Some(client_info),
+        Err(e) => {
+            println!("[SERVER] Failed to receive client info: {}", e);
+            connection.close(b"bad handshake");
+            None
+        }
+    }
+}
+
+// Client sends all of its commands over a single stream, right after the handshake one
+async fn receive_commands(
+    mut streams: BoxStream<'static, Result<RecvStream, NetworkError>>,
+    id: ClientId,
+    events_tx: &mut mpsc::Sender<(ClientId, ClientEvent)>,
+) -> Result<(), NetworkError> {
+    let mut stream = shared::network::accept_uni(&mut streams).await?;
+    let mut last_sequence = None;
+    loop {
+        let command = shared::network::receive::<ClientCommand>(&mut stream).await?;
+        // Stream keeps them in order, but a stale command must never override a newer one
+        if last_sequence.map_or(false, |last| command.sequence <= last) {
+            continue;
+        }
+        last_sequence = Some(command.sequence);
+        if events_tx
+            .send((id, ClientEvent::Command(command)))
+            .await
+            .is_err()
+        {
+            return Ok(());
+        }
+    }
+}
+
+async fn send_ordered(
+    connection: &dyn Connection,
+    server_info: shared::commands::ServerInfo,
+    snapshot: shared::commands::Snapshot,
+    mut ordered_rx: OutboundReceiver,
+) -> Result<(), Error> {
+    let mut stream = connection.open_uni().await?;
+    println!("[SERVER] Sending server info...");
+    shared::network::send(&mut stream, &HandshakeResponse::Accepted(server_info)).await?;
+    for chunk in snapshot.chunks(shared::commands::Snapshot::MAX_SIZE) {
+        shared::network::send(&mut stream, &chunk).await?;
+    }
+
+    shared::network::finish(&mut stream).await?;
+
+    let mut stream = connection.open_uni().await?;
+    while let Some(command) = ordered_rx.recv().await {
+        shared::network::send(&mut stream, &command).await?;
+    }
+    shared::network::finish(&mut stream).await?;
+    Ok(())
+}
+
+// Doesn't wait for the client to receive the reason
+fn reject(connection: Arc<dyn Connection>, reason: String) {
+    println!("[SERVER] Rejecting client: {}", reason);
+    tokio::spawn(async move {
+        if let Err(e) = send_rejection(&*connection, reason).await {
+            println!("[SERVER] Failed to send rejection: {}", e);
+        }
+        connection.close(b"rejected");
+    });
+}
+
+async fn send_rejection(connection: &dyn Connection, reason: String) -> Result<(), Error> {
+    let mut stream = connection.open_uni().await?;
+    shared::network::send(
+        &mut stream,
+        &HandshakeResponse::Rejected(shared::commands::ConnectRejected { reason }),
     )
+    .await?;
+    shared::network::finish(&mut stream).await?;
+    Ok(())
 }
 
-pub async fn spawn() {
-    let (certificate_chain, key) = generate_certificate();
+pub async fn spawn(config: config::Config) -> Result<(), Error> {
+    let (certificate_chain, key) = certificate::load_or_generate(
+        std::path::Path::new(&config.certificate),
+        std::path::Path::new(&config.key),
+    )?;
     let mut server_config = quinn::ServerConfigBuilder::default();
-    server_config.certificate(certificate_chain, key).unwrap();
+    server_config.certificate(certificate_chain, key)?;
     let mut endpoint = quinn::Endpoint::builder();
     endpoint.listen(server_config.build());
-    let addr = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 1234);
-    let (_, incoming) = endpoint
-        .with_socket(UdpSocket::bind(&addr).unwrap())
-        .unwrap();
-    let mut game = crate::base::game_manager::GameManager::new();
-    game.load_props();
-    let server = Server {
-        clients: DenseSlotMap::default(),
-        game: game,
-        tickrate: 60,
-    };
-    server.run(incoming).await;
+    let addr = config
+        .bind_address
+        .to_socket_addrs()
+        .with_context(|| format!("Invalid bind address {}", config.bind_address))?
+        .next()
+        .ok_or_else(|| anyhow!("{} doesn't resolve to anything", config.bind_address))?;
+    let socket =
+        UdpSocket::bind(&addr).with_context(|| format!("Can't bind to {}", config.bind_address))?;
+    let (_, incoming) = endpoint.with_socket(socket)?;
+    println!("[SERVER] Listening on {}", addr);
+    let server = Server::new(config)?;
+    server
+        .run(
+            shared::transport::quic::incoming(incoming),
+            admin_commands(),
+        )
+        .await;
+    Ok(())
+}
+
+// One command per line from stdin
+fn admin_commands() -> BoxStream<'static, String> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        use std::io::BufRead;
+        let stdin = std::io::stdin();
+        for line in stdin.lock().lines() {
+            match line {
+                Ok(line) if tx.send(line).is_ok() => {}
+                _ => return,
+            }
+        }
+    });
+    rx.boxed()
 }
 
 #[tokio::main]
 pub async fn run() {
     println!("[SERVER] Starting the server...");
-    spawn().await;
+    let config = match config::Config::from_args() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("[SERVER] {:#}", e);
+            return;
+        }
+    };
+    if let Err(e) = spawn(config).await {
+        println!("[SERVER] Failed to start: {:#}", e);
+    }
 }
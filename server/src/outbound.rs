@@ -0,0 +1,129 @@
+// Per-client outbound queue. Keeps one slow client from stalling the whole simulation:
+// reliable messages (spawns, despawns) are always delivered in order, while position updates go out as datagrams.
+// If datagrams are not available, position updates fall back to the ordered stream and get coalesced,
+// so only the newest one waits to be sent
+
+use shared::commands::{PositionUpdate, ServerMessage};
+use shared::transport::Connection;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+// Client that can't keep up with this many reliable messages is not going to recover
+const MAX_QUEUED: usize = 1024;
+
+#[derive(Debug)]
+pub enum OutboundError {
+    Closed,
+    Lagging,
+}
+
+#[derive(Default)]
+pub struct OutboundStats {
+    // Messages waiting to be written to the stream
+    pub queued: AtomicUsize,
+    // Position updates replaced by a newer one before they were sent
+    pub dropped_ticks: AtomicUsize,
+}
+
+#[derive(Default)]
+struct Shared {
+    positions: Mutex<Option<PositionUpdate>>,
+    stats: OutboundStats,
+}
+
+pub struct Outbound {
+    connection: Arc<dyn Connection>,
+    reliable: mpsc::UnboundedSender<ServerMessage>,
+    wake: mpsc::Sender<()>,
+    shared: Arc<Shared>,
+}
+
+pub struct OutboundReceiver {
+    reliable: mpsc::UnboundedReceiver<ServerMessage>,
+    wake: mpsc::Receiver<()>,
+    shared: Arc<Shared>,
+}
+
+pub fn channel(connection: Arc<dyn Connection>) -> (Outbound, OutboundReceiver) {
+    let (reliable_tx, reliable_rx) = mpsc::unbounded_channel();
+    // Single slot is enough, receiver always drains everything that's available after wake up
+    let (wake_tx, wake_rx) = mpsc::channel(1);
+    let shared = Arc::new(Shared::default());
+    (
+        Outbound {
+            connection,
+            reliable: reliable_tx,
+            wake: wake_tx,
+            shared: shared.clone(),
+        },
+        OutboundReceiver {
+            reliable: reliable_rx,
+            wake: wake_rx,
+            shared,
+        },
+    )
+}
+
+impl Outbound {
+    // Never blocks
+    pub fn push_reliable(&mut self, message: ServerMessage) -> Result<(), OutboundError> {
+        let stats = &self.shared.stats;
+        if stats.queued.load(Ordering::Relaxed) >= MAX_QUEUED {
+            return Err(OutboundError::Lagging);
+        }
+        self.reliable
+            .send(message)
+            .map_err(|_| OutboundError::Closed)?;
+        stats.queued.fetch_add(1, Ordering::Relaxed);
+        self.wake()
+    }
+    // Never blocks
+    pub fn push_positions(&mut self, update: PositionUpdate) -> Result<(), OutboundError> {
+        if let Some(max_size) = self.connection.max_datagram_size() {
+            let sent = update
+                .chunks(max_size)
+                .iter()
+                .all(|chunk| shared::network::send_datagram(&*self.connection, chunk).is_ok());
+            if sent {
+                return Ok(());
+            }
+        }
+        let stats = &self.shared.stats;
+        let stale = self.shared.positions.lock().unwrap().replace(update);
+        if stale.is_some() {
+            stats.dropped_ticks.fetch_add(1, Ordering::Relaxed);
+        } else {
+            stats.queued.fetch_add(1, Ordering::Relaxed);
+        }
+        self.wake()
+    }
+    pub fn stats(&self) -> &OutboundStats {
+        &self.shared.stats
+    }
+    fn wake(&mut self) -> Result<(), OutboundError> {
+        match self.wake.try_send(()) {
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(OutboundError::Closed),
+            // Receiver is already woken up
+            _ => Ok(()),
+        }
+    }
+}
+
+impl OutboundReceiver {
+    // Reliable messages go first, then the latest position update
+    pub async fn recv(&mut self) -> Option<ServerMessage> {
+        loop {
+            if let Ok(message) = self.reliable.try_recv() {
+                self.shared.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                return Some(message);
+            }
+            let positions = self.shared.positions.lock().unwrap().take();
+            if let Some(update) = positions {
+                self.shared.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                return Some(ServerMessage::Positions(update));
+            }
+            self.wake.recv().await?;
+        }
+    }
+}
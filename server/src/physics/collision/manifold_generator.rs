@@ -40,7 +40,7 @@ impl PlanetManifoldGenerator {
         // Find distance from m_a to m_b
         let dir = m_a.inverse_transform_point(bounds.center()).coords;
         let distance = dir.norm();
-        let cache = &mut *planet.cache.lock().unwrap();
+        let cache = &mut *planet.cache();
 
         for coords in shared::planet::Coords::neighborhood(
             planet.terrain.face_resolution(),
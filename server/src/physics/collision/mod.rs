@@ -25,16 +25,23 @@ use ncollide3d::narrow_phase::ContactAlgorithm;
 use ncollide3d::narrow_phase::ContactDispatcher;
 use ncollide3d::query::PointQuery;
 use ncollide3d::shape::{FeatureId, Shape};
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
 
 pub trait Terrain: Sync + Send {
     fn samples(&self, coords: &shared::planet::Coords, resolution: u32) -> Vec<f64>;
     fn face_resolution(&self) -> u32;
+    // Changes when terrain is regenerated, cached samples are useless after that
+    fn generation(&self) -> u64 {
+        0
+    }
 }
 
 pub struct PlanetCollision {
     resolution: u32,
     cache: Mutex<lru::LruCache<shared::planet::Coords, ChunkData>>,
+    // Terrain generation cache was filled from
+    cache_generation: AtomicU64,
     terrain: std::sync::Arc<dyn Terrain>,
     radius: f64,
 }
@@ -44,6 +51,7 @@ impl Clone for PlanetCollision {
         Self {
             terrain: self.terrain.clone(),
             cache: Mutex::new(lru::LruCache::new(self.cache.lock().unwrap().cap())),
+            cache_generation: AtomicU64::new(self.cache_generation.load(Ordering::Relaxed)),
             ..*self
         }
     }
@@ -56,12 +64,22 @@ impl PlanetCollision {
         radius: f64,
         cache_size: usize,
     ) -> Self {
+        let generation = terrain.generation();
         Self {
             terrain,
             resolution,
             radius,
             cache: Mutex::new(lru::LruCache::new(cache_size)),
+            cache_generation: AtomicU64::new(generation),
+        }
+    }
+    fn cache(&self) -> MutexGuard<lru::LruCache<shared::planet::Coords, ChunkData>> {
+        let mut cache = self.cache.lock().unwrap();
+        let generation = self.terrain.generation();
+        if self.cache_generation.swap(generation, Ordering::Relaxed) != generation {
+            cache.clear();
         }
+        cache
     }
     fn feature_id(
         &self,
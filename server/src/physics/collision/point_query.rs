@@ -32,7 +32,7 @@ impl PointQuery<f64> for PlanetCollision {
             &na::convert(local.coords),
         );
         let distance2 = |x: &na::Point3<f64>| na::distance_squared(x, &local);
-        let cache = &mut *self.cache.lock().unwrap();
+        let cache = &mut *self.cache();
         let data = if let Some(x) = cache.get(&coords) {
             x
         } else {
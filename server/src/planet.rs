@@ -1,20 +1,111 @@
 // Basicly the planet from client side, but without cache manager
 
 use crate::physics::collision::Terrain;
+use shared::planet::procgen::{Layer, PlanetProcGen};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::{Mutex, RwLock};
+use std::time::SystemTime;
+
+type Loaded = Result<Option<(u64, Vec<Layer>)>, String>;
 
 pub struct Planet {
-    pub procgen: shared::planet::procgen::PlanetProcGen,
+    // Layers can be hot-reloaded while physics is using them
+    pub procgen: RwLock<PlanetProcGen>,
     pub radius: f64,
+    generation: AtomicU64,
+    // Layers file, empty if they didn't come from one
+    path: String,
+    reload: Mutex<Reload>,
+}
+
+// File is read and parsed on a thread of its own, terrain sampling only waits for the swap
+struct Reload {
+    modified: Option<SystemTime>,
+    loading: Option<Receiver<Loaded>>,
+}
+
+impl Planet {
+    pub fn new(procgen: PlanetProcGen, radius: f64) -> Self {
+        let path = procgen.file_path.clone();
+        Self {
+            procgen: RwLock::new(procgen),
+            radius,
+            generation: AtomicU64::new(0),
+            reload: Mutex::new(Reload {
+                modified: modified(&path),
+                loading: None,
+            }),
+            path,
+        }
+    }
+    pub fn layers(&self) -> Vec<Layer> {
+        self.procgen.read().unwrap().layers.clone()
+    }
+    // Returns new layers once the changed file has been loaded. Doesn't block, call it as often as needed
+    pub fn try_reload(&self) -> Option<Vec<Layer>> {
+        let mut reload = self.reload.lock().unwrap();
+        if let Some(loading) = &reload.loading {
+            let loaded = match loading.try_recv() {
+                Ok(loaded) => loaded,
+                Err(TryRecvError::Empty) => return None,
+                Err(TryRecvError::Disconnected) => Err("loader thread has died".to_string()),
+            };
+            reload.loading = None;
+            return match loaded {
+                Ok(Some((hash, layers))) => {
+                    self.procgen
+                        .write()
+                        .unwrap()
+                        .set_layers(hash, layers.clone());
+                    self.generation.fetch_add(1, Ordering::Relaxed);
+                    Some(layers)
+                }
+                // Touched, but nothing has changed
+                Ok(None) => None,
+                // Broken file is not retried until it changes again
+                Err(e) => {
+                    println!(
+                        "[SERVER] Failed to reload planet layers from {}: {}",
+                        self.path, e
+                    );
+                    None
+                }
+            };
+        }
+        // File may be missing for a moment while an editor saves it
+        let modified = modified(&self.path);
+        if modified.is_none() || modified == reload.modified {
+            return None;
+        }
+        reload.modified = modified;
+        let path = self.path.clone();
+        let known_hash = self.procgen.read().unwrap().file_hash;
+        let (loaded_tx, loaded) = mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = loaded_tx.send(PlanetProcGen::read_layers(&path, known_hash));
+        });
+        reload.loading = Some(loaded);
+        None
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    if path.is_empty() {
+        return None;
+    }
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
 }
 
 impl Terrain for Planet {
     fn samples(&self, coords: &shared::planet::Coords, resolution: u32) -> Vec<f64> {
+        let procgen = self.procgen.read().unwrap();
         let mut out = Vec::with_capacity(resolution.pow(2) as usize);
         for sample in coords.samples(self.face_resolution(), resolution) {
             out.push(
-                self.procgen
-                    .get(na::Point3::from(sample.into_inner() * self.radius), u8::MAX)
-                    / 12.0,
+                procgen.get(na::Point3::from(sample.into_inner() * self.radius), u8::MAX) / 12.0,
             );
             //out.push(1000.0)
         }
@@ -23,4 +114,7 @@ impl Terrain for Planet {
     fn face_resolution(&self) -> u32 {
         2u32.pow(15)
     }
+    fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Relaxed)
+    }
 }
@@ -0,0 +1,67 @@
+// Players whose connection was lost are kept in the world for a while, so their client can reconnect
+// and carry on with the same character and whatever it was holding.
+// Every connection gets a new token in ServerInfo, client presents it in ClientInfo when it comes back
+
+use std::collections::HashMap;
+
+struct Detached {
+    entity: hecs::Entity,
+    // Player is despawned once the server gets to this tick
+    expires: u64,
+}
+
+#[derive(Default)]
+pub struct Sessions {
+    detached: HashMap<u64, Detached>,
+}
+
+impl Sessions {
+    pub fn new_token() -> u64 {
+        rand::random()
+    }
+    pub fn detach(&mut self, token: u64, entity: hecs::Entity, expires: u64) {
+        self.detached.insert(token, Detached { entity, expires });
+    }
+    // Token is no good after that, the new connection gets its own
+    pub fn resume(&mut self, token: u64) -> Option<hecs::Entity> {
+        self.detached.remove(&token).map(|detached| detached.entity)
+    }
+    // Players that didn't come back in time
+    pub fn expire(&mut self, tick: u64) -> Vec<hecs::Entity> {
+        let mut expired = vec![];
+        self.detached.retain(|_, detached| {
+            if detached.expires > tick {
+                return true;
+            }
+            expired.push(detached.entity);
+            false
+        });
+        expired
+    }
+    // Players waiting for their client
+    pub fn count(&self) -> usize {
+        self.detached.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn players_wait_until_they_expire() {
+        let mut world = hecs::World::new();
+        let (first, second) = (world.spawn((1u8,)), world.spawn((2u8,)));
+        let mut sessions = Sessions::default();
+        sessions.detach(1, first, 10);
+        sessions.detach(2, second, 20);
+        assert_eq!(sessions.expire(9), vec![]);
+        assert_eq!(sessions.expire(10), vec![first]);
+        assert_eq!(sessions.count(), 1);
+        assert_eq!(sessions.resume(1), None);
+        assert_eq!(sessions.resume(2), Some(second));
+        // Only once
+        assert_eq!(sessions.resume(2), None);
+        assert_eq!(sessions.expire(100), vec![]);
+    }
+}
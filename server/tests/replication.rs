@@ -0,0 +1,386 @@
+// Whole server with several clients connected over the in-memory transport.
+// Clients speak the protocol directly, no rendering or prediction involved
+
+extern crate nalgebra as na;
+
+use futures::io::AsyncWriteExt;
+use futures::StreamExt;
+use server::config::Config;
+use server::Server;
+use shared::commands::*;
+use shared::network;
+use shared::transport::{loopback, NewConnection, RecvStream, SendStream};
+use shared::EntityId;
+use std::collections::HashSet;
+use std::time::Duration;
+
+// Generous, physics runs unoptimized in tests
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+struct TestClient {
+    connection: NewConnection,
+    id: EntityId,
+    session: u64,
+    // Entities client currently knows about
+    known: HashSet<EntityId>,
+    commands: SendStream,
+    ordered: RecvStream,
+}
+
+impl TestClient {
+    async fn join(connector: &loopback::Connector, name: &str) -> Self {
+        let connection = connector.connect().unwrap();
+        Self::handshake(connection, ClientInfo::new(name.to_string())).await
+    }
+    async fn handshake(mut connection: NewConnection, info: ClientInfo) -> Self {
+        let mut stream = connection.connection.open_uni().await.unwrap();
+        network::send(&mut stream, &info).await.unwrap();
+        network::finish(&mut stream).await.unwrap();
+
+        let mut stream = network::accept_uni(&mut connection.uni_streams)
+            .await
+            .unwrap();
+        let info = match network::receive::<HandshakeResponse>(&mut stream)
+            .await
+            .unwrap()
+        {
+            HandshakeResponse::Accepted(info) => info,
+            HandshakeResponse::Rejected(rejection) => panic!("rejected: {}", rejection.reason),
+        };
+        let mut known = HashSet::new();
+        loop {
+            let chunk = network::receive::<Snapshot>(&mut stream).await.unwrap();
+            known.extend(chunk.entities.iter().map(|(id, _)| *id));
+            if chunk.last {
+                break;
+            }
+        }
+
+        let commands = connection.connection.open_uni().await.unwrap();
+        let ordered = network::accept_uni(&mut connection.uni_streams)
+            .await
+            .unwrap();
+        Self {
+            connection,
+            id: EntityId(info.character_id),
+            session: info.session,
+            known,
+            commands,
+            ordered,
+        }
+    }
+    // Applies ticks until the condition holds
+    async fn wait_until(&mut self, condition: impl Fn(&HashSet<EntityId>) -> bool) {
+        let known = &mut self.known;
+        let ordered = &mut self.ordered;
+        let waiting = async {
+            while !condition(known) {
+                if let ServerMessage::Tick(tick) =
+                    network::receive::<ServerMessage>(ordered).await.unwrap()
+                {
+                    known.extend(tick.spawns.iter().map(|(id, _)| *id));
+                    for id in &tick.despawns {
+                        known.remove(id);
+                    }
+                }
+            }
+        };
+        tokio::time::timeout(TIMEOUT, waiting)
+            .await
+            .expect("timed out waiting for tick");
+    }
+    // Reads whatever is left on the ordered stream, until the server closes the connection
+    async fn wait_for_close(&mut self) {
+        let ordered = &mut self.ordered;
+        let waiting = async { while network::receive::<ServerMessage>(ordered).await.is_ok() {} };
+        tokio::time::timeout(TIMEOUT, waiting)
+            .await
+            .expect("timed out waiting for server to close the connection");
+    }
+    async fn send(&mut self, command: &ClientCommand) {
+        network::send(&mut self.commands, command).await.unwrap();
+    }
+    // Newest acked input from position updates
+    async fn wait_for_ack(&mut self, sequence: u32) {
+        let datagrams = &mut self.connection.datagrams;
+        let waiting = async {
+            while let Some(Ok(datagram)) = datagrams.next().await {
+                let update = network::decode_datagram::<PositionUpdate>(&datagram).unwrap();
+                if update.input_ack >= sequence {
+                    return;
+                }
+            }
+            panic!("connection closed");
+        };
+        tokio::time::timeout(TIMEOUT, waiting)
+            .await
+            .expect("timed out waiting for ack");
+    }
+}
+
+// Server gets a thread and a runtime of its own, same as when it runs next to a client
+fn start() -> loopback::Connector {
+    // Default config points to ./assets
+    std::env::set_current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/..")).unwrap();
+    let config = Config {
+        tickrate: 20,
+        max_players: 3,
+        ..Default::default()
+    };
+    let (connector, incoming) = loopback::listen();
+    let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let server = match Server::new(config) {
+            Ok(server) => server,
+            Err(e) => {
+                let _ = ready_tx.send(Err(format!("{:#}", e)));
+                return;
+            }
+        };
+        let _ = ready_tx.send(Ok(()));
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        // No admin commands in tests
+        runtime.block_on(server.run(incoming, futures::stream::pending().boxed()));
+    });
+    ready_rx.recv().unwrap().expect("server failed to start");
+    connector
+}
+
+fn command(sequence: u32) -> ClientCommand {
+    ClientCommand {
+        sequence,
+        tick_ack: 0,
+        movement_direction: na::Vector2::new(0, 127),
+        orientation: na::UnitQuaternion::identity(),
+        fly: false,
+        jump: false,
+        run: true,
+        sit: false,
+        pickup: false,
+        prop_spawn: None,
+    }
+}
+
+#[tokio::test]
+async fn clients_see_each_other() {
+    let connector = start();
+
+    let mut first = TestClient::join(&connector, "first").await;
+    assert!(first.known.contains(&first.id));
+    let mut second = TestClient::join(&connector, "second").await;
+    assert!(second.known.contains(&first.id));
+    assert!(second.known.contains(&second.id));
+    let second_id = second.id;
+    first.wait_until(|known| known.contains(&second_id)).await;
+
+    let mut third = TestClient::join(&connector, "third").await;
+    let third_id = third.id;
+    for client in &mut [&mut first, &mut second] {
+        client.wait_until(|known| known.contains(&third_id)).await;
+    }
+
+    // Commands reach the simulation and get acked
+    for sequence in 1..=5 {
+        third.send(&command(sequence)).await;
+    }
+    third.wait_for_ack(5).await;
+
+    // Everyone else sees the player leave
+    second.connection.connection.close(b"bye");
+    for client in &mut [&mut first, &mut third] {
+        client.wait_until(|known| !known.contains(&second_id)).await;
+    }
+}
+
+#[tokio::test]
+async fn lost_client_gets_its_player_back() {
+    let connector = start();
+
+    let mut first = TestClient::join(&connector, "first").await;
+    let (connection, cable) = connector.connect_with_cable().unwrap();
+    let mut second = TestClient::handshake(connection, ClientInfo::new("second".to_string())).await;
+    second.send(&command(1)).await;
+    second.wait_for_ack(1).await;
+    let second_id = second.id;
+    first.wait_until(|known| known.contains(&second_id)).await;
+
+    cable.cut();
+    let info = ClientInfo {
+        session: Some(second.session),
+        ..ClientInfo::new("second".to_string())
+    };
+    let mut resumed = TestClient::handshake(connector.connect().unwrap(), info).await;
+    assert_eq!(resumed.id, second_id);
+    assert!(resumed.known.contains(&second_id));
+    assert_ne!(resumed.session, second.session);
+    resumed.send(&command(2)).await;
+    resumed.wait_for_ack(2).await;
+
+    // Token only works once
+    let info = ClientInfo {
+        session: Some(second.session),
+        ..ClientInfo::new("third".to_string())
+    };
+    let third = TestClient::handshake(connector.connect().unwrap(), info).await;
+    assert_ne!(third.id, second_id);
+    // Ticks arrive in order, so if the player was ever despawned first has seen it by now
+    let third_id = third.id;
+    first.wait_until(|known| known.contains(&third_id)).await;
+    assert!(first.known.contains(&second_id));
+}
+
+#[tokio::test]
+async fn leaving_client_is_despawned_right_away() {
+    let connector = start();
+
+    let mut first = TestClient::join(&connector, "first").await;
+    let mut second = TestClient::join(&connector, "second").await;
+    let second_id = second.id;
+    first.wait_until(|known| known.contains(&second_id)).await;
+
+    // What the real client does when its window is closed
+    network::finish(&mut second.commands).await.unwrap();
+    second.connection.connection.close(b"bye");
+    // Lost one would stay for the whole session grace, 30s by default
+    tokio::time::timeout(
+        Duration::from_secs(10),
+        first.wait_until(|known| !known.contains(&second_id)),
+    )
+    .await
+    .expect("player was kept around");
+
+    // Player wasn't kept around for the session to come back to
+    let info = ClientInfo {
+        session: Some(second.session),
+        ..ClientInfo::new("second".to_string())
+    };
+    let resumed = TestClient::handshake(connector.connect().unwrap(), info).await;
+    assert_ne!(resumed.id, second_id);
+}
+
+// Length prefix in front of whatever the payload is
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let len = (payload.len() as u32).to_le_bytes();
+    [&len[..3], payload].concat()
+}
+
+// Sends client info frame as is, returns the rejection if there is one
+async fn rejection(connector: &loopback::Connector, info: &[u8]) -> Option<ConnectRejected> {
+    let mut connection = connector.connect().unwrap();
+    let mut stream = connection.connection.open_uni().await.unwrap();
+    stream.write_all(&frame(info)).await.unwrap();
+    network::finish(&mut stream).await.unwrap();
+
+    let mut stream = network::accept_uni(&mut connection.uni_streams)
+        .await
+        .unwrap();
+    match network::receive::<HandshakeResponse>(&mut stream).await {
+        Ok(HandshakeResponse::Rejected(rejection)) => Some(rejection),
+        _ => None,
+    }
+}
+
+#[tokio::test]
+async fn mismatched_protocol_is_rejected() {
+    let connector = start();
+
+    let mut info = ClientInfo::new("old".to_string());
+    info.protocol_version -= 1;
+    let frame = network::serialize(&info).unwrap();
+    let rejection = rejection(&connector, &frame[3..]).await;
+    assert!(rejection
+        .expect("client wasn't rejected")
+        .reason
+        .contains("protocol version mismatch"));
+
+    // Before sessions, client info ended with the name
+    let old = [
+        &(PROTOCOL_VERSION - 1).to_le_bytes()[..],
+        &9u64.to_le_bytes(),
+        b"old build",
+        &3u64.to_le_bytes(),
+        b"old",
+    ]
+    .concat();
+    assert!(network::decode::<ClientInfo>(&old).is_err());
+    let rejection = rejection(&connector, &old).await;
+    let reason = rejection.expect("old client wasn't rejected").reason;
+    assert!(reason.contains("protocol version mismatch"), "{}", reason);
+    assert!(reason.contains("old build"), "{}", reason);
+}
+
+#[tokio::test]
+async fn malformed_commands_only_drop_their_client() {
+    let connector = start();
+
+    let mut good = TestClient::join(&connector, "good").await;
+    let valid = network::serialize(&command(7)).unwrap()[3..].to_vec();
+    // Bool that is neither 0 nor 1
+    let mut bad_bool = valid.clone();
+    let jump = bad_bool.len() - 6;
+    bad_bool[jump] = 2;
+    let mut frames: Vec<_> = vec![
+        vec![],
+        vec![0xff; 3],
+        // Truncated
+        valid[..valid.len() - 1].to_vec(),
+        // Trailing garbage
+        [valid.clone(), vec![0xff; 4]].concat(),
+        bad_bool,
+    ]
+    .iter()
+    .map(|payload| frame(payload))
+    .collect();
+    // Longest frame there can be, with nothing after it
+    frames.push(vec![0xff; 3]);
+    for (i, frame) in frames.iter().enumerate() {
+        let mut bad = TestClient::join(&connector, "bad").await;
+        let bad_id = bad.id;
+        good.wait_until(|known| known.contains(&bad_id)).await;
+
+        bad.commands.write_all(frame).await.unwrap();
+        bad.wait_for_close().await;
+        // It didn't lose connection, so its player is not kept around
+        good.wait_until(|known| !known.contains(&bad_id)).await;
+
+        let sequence = i as u32 + 1;
+        good.send(&command(sequence)).await;
+        good.wait_for_ack(sequence).await;
+    }
+}
+
+#[tokio::test]
+async fn stalled_handshake_doesnt_hold_up_ticks() {
+    let connector = start();
+
+    let mut good = TestClient::join(&connector, "good").await;
+    // Half of client info, then nothing
+    let info = network::serialize(&ClientInfo::new("stalled".to_string())).unwrap();
+    let stalled = connector.connect().unwrap();
+    let mut stalled_stream = stalled.connection.open_uni().await.unwrap();
+    stalled_stream
+        .write_all(&info[..info.len() / 2])
+        .await
+        .unwrap();
+    // Never even opens the stream
+    let _silent = connector.connect().unwrap();
+    // Truncated client info, stream is finished right after it
+    let mut truncated = connector.connect().unwrap();
+    let mut stream = truncated.connection.open_uni().await.unwrap();
+    stream.write_all(&info[..info.len() - 1]).await.unwrap();
+    network::finish(&mut stream).await.unwrap();
+
+    for sequence in 1..=20 {
+        good.send(&command(sequence)).await;
+        good.wait_for_ack(sequence).await;
+    }
+    let closed = tokio::time::timeout(TIMEOUT, network::accept_uni(&mut truncated.uni_streams))
+        .await
+        .expect("truncated handshake wasn't closed");
+    assert!(closed.is_err());
+    // Others still get in while the stalled one waits
+    let late = TestClient::join(&connector, "late").await;
+    let late_id = late.id;
+    good.wait_until(|known| known.contains(&late_id)).await;
+    drop((stalled, stalled_stream));
+}
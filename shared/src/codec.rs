@@ -0,0 +1,227 @@
+// Compact transform encoding for position updates.
+// Position is stored relative to the planet surface: a cube-sphere cell, quantized offset inside of the cell and altitude.
+// Rotation uses smallest-three quaternion compression
+
+use crate::planet::{Coords, Face};
+use crate::EntityId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Cells per face edge, a cell is planet::Coords at this resolution.
+// With 1275km radius cell is ~600m wide, offset step is then ~1cm
+pub const CELL_RESOLUTION: u32 = 4096;
+// Altitude is stored in 1/256 of a meter
+const ALTITUDE_SCALE: f64 = 256.0;
+const OFFSET_MAX: f64 = u16::MAX as f64;
+const ROTATION_BITS: u32 = 10;
+const ROTATION_MAX: u32 = (1 << ROTATION_BITS) - 1;
+
+// Entity is resent if it moved further than that
+const POSITION_THRESHOLD: f64 = 0.01;
+const ROTATION_THRESHOLD: f64 = 0.005;
+// Datagrams get lost, so even static entities are refreshed once in a while
+const REFRESH_TICKS: u64 = 60;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuantizedTransform {
+    pub face: u8,
+    pub cell: (u16, u16),
+    pub offset: (u16, u16),
+    pub altitude: i32,
+    pub rotation: u32,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct TransformCodec {
+    radius: f64,
+}
+
+impl TransformCodec {
+    pub fn new(radius: f64) -> Self {
+        Self { radius }
+    }
+    pub fn encode(&self, isometry: &na::Isometry3<f64>) -> QuantizedTransform {
+        let position = isometry.translation.vector;
+        let cell = Coords::from_vector(CELL_RESOLUTION, &position);
+        let (_, unit_coords) = Face::coords(&position);
+        let origin = na::Vector2::new(cell.coords.0 as f64, cell.coords.1 as f64);
+        let offset = (unit_coords.coords * CELL_RESOLUTION as f64 - origin)
+            .map(|x| na::clamp((x * OFFSET_MAX).round(), 0.0, OFFSET_MAX));
+        let altitude = ((position.norm() - self.radius) * ALTITUDE_SCALE).round();
+        QuantizedTransform {
+            face: cell.face as u8,
+            cell: (cell.coords.0 as u16, cell.coords.1 as u16),
+            offset: (offset.x as u16, offset.y as u16),
+            altitude: na::clamp(altitude, i32::MIN as f64, i32::MAX as f64) as i32,
+            rotation: encode_rotation(&isometry.rotation),
+        }
+    }
+    // Returns None if the data is malformed
+    pub fn decode(&self, transform: &QuantizedTransform) -> Option<na::Isometry3<f64>> {
+        let face = Face::iter().nth(transform.face as usize)?;
+        let cell = Coords {
+            coords: (transform.cell.0 as u32, transform.cell.1 as u32),
+            face,
+        };
+        let offset = na::Point2::new(transform.offset.0 as f64, transform.offset.1 as f64);
+        let direction = cell.direction(CELL_RESOLUTION, &(offset / OFFSET_MAX));
+        let distance = self.radius + transform.altitude as f64 / ALTITUDE_SCALE;
+        Some(na::Isometry3::from_parts(
+            na::Translation3::from(direction.into_inner() * distance),
+            decode_rotation(transform.rotation),
+        ))
+    }
+}
+
+// Stores the largest component's index in two high bits, the other three take ROTATION_BITS each.
+// Largest component is recovered from unit length, q and -q are the same rotation, so its sign is always positive
+fn encode_rotation(rotation: &na::UnitQuaternion<f64>) -> u32 {
+    let coords = rotation.coords;
+    let largest = coords.iamax();
+    let sign = if coords[largest] < 0.0 { -1.0 } else { 1.0 };
+    let mut packed = largest as u32;
+    for i in (0..4).filter(|&i| i != largest) {
+        // Smaller components are within [-1/sqrt(2), 1/sqrt(2)]
+        let x = (coords[i] * sign * std::f64::consts::SQRT_2 + 1.0) / 2.0;
+        let x = na::clamp((x * ROTATION_MAX as f64).round(), 0.0, ROTATION_MAX as f64);
+        packed = (packed << ROTATION_BITS) | x as u32;
+    }
+    packed
+}
+
+fn decode_rotation(packed: u32) -> na::UnitQuaternion<f64> {
+    let largest = (packed >> (ROTATION_BITS * 3)) as usize & 3;
+    let mut coords = [0.0; 4];
+    let mut sum = 0.0;
+    let mut shift = ROTATION_BITS * 3;
+    for i in (0..4).filter(|&i| i != largest) {
+        shift -= ROTATION_BITS;
+        let x = ((packed >> shift) & ROTATION_MAX) as f64 / ROTATION_MAX as f64;
+        coords[i] = (x * 2.0 - 1.0) / std::f64::consts::SQRT_2;
+        sum += coords[i] * coords[i];
+    }
+    coords[largest] = (1.0 - sum).max(0.0).sqrt();
+    // Quaternion coords are stored as (i, j, k, w)
+    na::UnitQuaternion::new_normalize(na::Quaternion::new(
+        coords[3], coords[0], coords[1], coords[2],
+    ))
+}
+
+// Picks entities that changed enough since they were sent last time
+#[derive(Default)]
+pub struct DeltaFilter {
+    sent: HashMap<EntityId, (na::Isometry3<f64>, u64)>,
+}
+
+impl DeltaFilter {
+    pub fn filter(
+        &mut self,
+        tick: u64,
+        codec: &TransformCodec,
+        transforms: impl IntoIterator<Item = (EntityId, na::Isometry3<f64>)>,
+    ) -> Vec<(EntityId, QuantizedTransform)> {
+        let mut changed = vec![];
+        for (id, isometry) in transforms {
+            if let Some((sent, sent_at)) = self.sent.get(&id) {
+                let moved = (sent.translation.vector - isometry.translation.vector).norm();
+                let rotated = sent.rotation.angle_to(&isometry.rotation);
+                if moved < POSITION_THRESHOLD
+                    && rotated < ROTATION_THRESHOLD
+                    && tick.saturating_sub(*sent_at) < REFRESH_TICKS
+                {
+                    continue;
+                }
+            }
+            self.sent.insert(id, (isometry, tick));
+            changed.push((id, codec.encode(&isometry)));
+        }
+        changed
+    }
+    // Should be called when entity is despawned
+    pub fn forget(&mut self, id: &EntityId) {
+        self.sent.remove(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use std::f64::consts::{FRAC_PI_2, PI};
+
+    const RADIUS: f64 = 1275620.0;
+
+    fn random_isometry(rng: &mut impl Rng, max_altitude: f64) -> na::Isometry3<f64> {
+        let direction = na::Unit::new_normalize(na::Vector3::new(
+            rng.gen_range(-1.0, 1.0),
+            rng.gen_range(-1.0, 1.0),
+            rng.gen_range(-1.0, 1.0),
+        ));
+        let altitude = rng.gen_range(-100.0, max_altitude);
+        na::Isometry3::from_parts(
+            na::Translation3::from(direction.into_inner() * (RADIUS + altitude)),
+            na::UnitQuaternion::from_euler_angles(
+                rng.gen_range(-PI, PI),
+                rng.gen_range(-FRAC_PI_2, FRAC_PI_2),
+                rng.gen_range(-PI, PI),
+            ),
+        )
+    }
+
+    #[test]
+    fn reconstruction_error_is_bounded() {
+        let codec = TransformCodec::new(RADIUS);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        for _ in 0..10000 {
+            let isometry = random_isometry(&mut rng, 10000.0);
+            let decoded = codec.decode(&codec.encode(&isometry)).unwrap();
+            let position_error = (decoded.translation.vector - isometry.translation.vector).norm();
+            let rotation_error = decoded.rotation.angle_to(&isometry.rotation);
+            assert!(position_error < 0.02, "position error {}", position_error);
+            assert!(rotation_error < 0.005, "rotation error {}", rotation_error);
+        }
+    }
+
+    #[test]
+    fn face_edges_roundtrip() {
+        let codec = TransformCodec::new(RADIUS);
+        let corner = na::Vector3::new(1.0, 1.0, 1.0).normalize() * RADIUS;
+        for position in &[
+            corner,
+            -corner,
+            na::Vector3::new(RADIUS, 0.0, 0.0),
+            na::Vector3::new(0.0, -RADIUS, 0.0),
+        ] {
+            let isometry = na::Isometry3::translation(position.x, position.y, position.z);
+            let decoded = codec.decode(&codec.encode(&isometry)).unwrap();
+            assert!((decoded.translation.vector - position).norm() < 0.02);
+        }
+    }
+
+    #[test]
+    fn malformed_face_is_rejected() {
+        let codec = TransformCodec::new(RADIUS);
+        let mut transform = codec.encode(&na::Isometry3::translation(RADIUS, 0.0, 0.0));
+        transform.face = 6;
+        assert!(codec.decode(&transform).is_none());
+    }
+
+    #[test]
+    fn delta_filter_skips_static_entities() {
+        let codec = TransformCodec::new(RADIUS);
+        let mut filter = DeltaFilter::default();
+        let still = na::Isometry3::translation(RADIUS, 0.0, 0.0);
+        let mut moving = na::Isometry3::translation(0.0, RADIUS, 0.0);
+        let entities = |moving| vec![(EntityId(0), still), (EntityId(1), moving)];
+
+        assert_eq!(filter.filter(0, &codec, entities(moving)).len(), 2);
+        moving.translation.vector.x += 1.0;
+        let changed = filter.filter(1, &codec, entities(moving));
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].0, EntityId(1));
+        // Static entity is refreshed eventually
+        let changed = filter.filter(REFRESH_TICKS, &codec, entities(moving));
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].0, EntityId(0));
+    }
+}
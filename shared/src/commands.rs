@@ -1,26 +1,221 @@
+use crate::codec::QuantizedTransform;
 use crate::components::*;
+use crate::network::{Message, MAX_MESSAGE_LENGTH};
+use crate::planet::procgen::Layer;
 use crate::EntityId;
 use serde::{Deserialize, Serialize};
 
+// Bump it every time anything that goes over the wire changes
+pub const PROTOCOL_VERSION: u32 = 13;
+// Commit the binary was built from. Informational, protocol version decides compatibility
+pub const BUILD_HASH: &str = env!("RECYCLERS_BUILD_HASH");
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ClientInfo {
+    // NOTE: These two must stay first and never change, see ClientVersion
+    pub protocol_version: u32,
+    pub build_hash: String,
     pub name: String,
+    // Token from the last ServerInfo, when reconnecting. Gets the same character back if server still has it
+    pub session: Option<u64>,
+}
+
+impl Message for ClientInfo {
+    // Mostly the name
+    const MAX_SIZE: usize = 4 * 1024;
+}
+
+// What every ClientInfo starts with, whatever build it's from. Server reads it before the rest,
+// so a client with a different layout is still told about the version mismatch
+#[derive(Debug, Deserialize)]
+pub struct ClientVersion {
+    pub protocol_version: u32,
+    pub build_hash: String,
+}
+
+impl ClientInfo {
+    pub fn new(name: String) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            build_hash: BUILD_HASH.to_string(),
+            name,
+            session: None,
+        }
+    }
 }
 
-// TODO: Send layer configuration
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ServerInfo {
+    pub protocol_version: u32,
+    pub build_hash: String,
     pub character_id: u32,
     pub tickrate: u8,
     pub planet_seed: u16,
-    pub planet_radius: u32,
+    // Also used as a reference for transform codec
+    pub planet_radius: f64,
+    // Client generates terrain from these, so it matches what server collides with
+    pub planet_layers: Vec<Layer>,
+    // New one for every connection, see ClientInfo::session
+    pub session: u64,
+    // Server turns fly off otherwise, client shouldn't predict it either
+    pub allow_fly: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConnectRejected {
+    pub reason: String,
+}
+
+// First message server sends on connect
+// NOTE: Don't reorder variants, clients from other builds have to be able to read rejections
+#[derive(Debug, Serialize, Deserialize)]
+pub enum HandshakeResponse {
+    Rejected(ConnectRejected),
+    Accepted(ServerInfo),
+}
+
+impl Message for HandshakeResponse {
+    // Planet layers are the biggest part
+    const MAX_SIZE: usize = 1024 * 1024;
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Tick {
+    // Increases by one every server tick, position updates use the same numbering
+    pub tick: u64,
     pub spawns: Vec<(EntityId, Vec<Component>)>,
-    // I hate the fact that we utilize f64s for position updates. This just makes every other netcode optimization dull
-    pub positions: Vec<(EntityId, na::Isometry3<f64>)>,
+    pub despawns: Vec<EntityId>,
+    // Welds that came into view and ones that are gone, see Weld::ends
+    pub welds: Vec<Weld>,
+    pub unwelds: Vec<(EntityId, EntityId)>,
+}
+
+// World state client starts from, taken at `tick`. Sent right after ServerInfo.
+// Big worlds don't fit into a single frame, so it comes in chunks, see Snapshot::chunks
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub tick: u64,
+    pub entities: Vec<(EntityId, Vec<Component>)>,
+    pub welds: Vec<Weld>,
+    // Nothing else follows, client can apply ticks after this one
+    pub last: bool,
+}
+
+impl Message for Snapshot {
+    // Chunks are as big as a frame can be
+    const MAX_SIZE: usize = MAX_MESSAGE_LENGTH;
+}
+
+impl Snapshot {
+    // Splits snapshot into pieces that fit into `max_size` bytes each
+    pub fn chunks(self, max_size: usize) -> Vec<Snapshot> {
+        let tick = self.tick;
+        let empty = || Snapshot {
+            tick,
+            entities: vec![],
+            welds: vec![],
+            last: false,
+        };
+        let header_size = bincode::serialized_size(&empty()).unwrap() as usize;
+        let mut chunks = vec![];
+        let mut chunk = empty();
+        let mut size = header_size;
+        for entity in self.entities {
+            let entry_size = bincode::serialized_size(&entity).unwrap() as usize;
+            if size + entry_size > max_size && !chunk.entities.is_empty() {
+                chunks.push(std::mem::replace(&mut chunk, empty()));
+                size = header_size;
+            }
+            size += entry_size;
+            chunk.entities.push(entity);
+        }
+        for weld in self.welds {
+            let entry_size = bincode::serialized_size(&weld).unwrap() as usize;
+            if size + entry_size > max_size
+                && (!chunk.entities.is_empty() || !chunk.welds.is_empty())
+            {
+                chunks.push(std::mem::replace(&mut chunk, empty()));
+                size = header_size;
+            }
+            size += entry_size;
+            chunk.welds.push(weld);
+        }
+        chunk.last = true;
+        chunks.push(chunk);
+        chunks
+    }
+}
+
+// Two props fixed together. Anchors are relative to each prop's transform
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Weld {
+    pub first: EntityId,
+    pub second: EntityId,
+    pub first_anchor: na::Isometry3<f64>,
+    pub second_anchor: na::Isometry3<f64>,
+}
+
+impl Weld {
+    // Identifies the weld, two props are only welded together once
+    pub fn ends(&self) -> (EntityId, EntityId) {
+        (self.first, self.second)
+    }
+}
+
+// Sent as unreliable datagrams, so it's stamped with the tick it was taken at.
+// Newer updates supersede older ones
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PositionUpdate {
+    pub tick: u64,
+    // Sequence number of the last command server applied for this client, see ClientCommand
+    pub input_ack: u32,
+    // Only entities that changed since the last update, see codec::DeltaFilter
+    pub positions: Vec<(EntityId, QuantizedTransform)>,
+}
+
+impl Message for PositionUpdate {
+    // Sent as a datagram, those can't be any bigger
+    const MAX_SIZE: usize = u16::MAX as usize;
+}
+
+impl PositionUpdate {
+    // Splits update into pieces that fit into `max_size` bytes each
+    pub fn chunks(&self, max_size: usize) -> Vec<PositionUpdate> {
+        let entry_size = match self.positions.first() {
+            Some(entry) => bincode::serialized_size(entry).unwrap() as usize,
+            // Still carries the input ack
+            None => return vec![self.clone()],
+        };
+        let header_size = bincode::serialized_size(&PositionUpdate {
+            tick: self.tick,
+            input_ack: self.input_ack,
+            positions: vec![],
+        })
+        .unwrap() as usize;
+        let per_chunk = (max_size.saturating_sub(header_size) / entry_size).max(1);
+        self.positions
+            .chunks(per_chunk)
+            .map(|positions| PositionUpdate {
+                tick: self.tick,
+                input_ack: self.input_ack,
+                positions: positions.to_vec(),
+            })
+            .collect()
+    }
+}
+
+// Everything server sends over the ordered stream after the handshake
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ServerMessage {
+    Tick(Tick),
+    // Fallback for connections that can't carry datagrams
+    Positions(PositionUpdate),
+    // Server has reloaded planet layers
+    Layers(Vec<Layer>),
+}
+
+impl Message for ServerMessage {
+    const MAX_SIZE: usize = MAX_MESSAGE_LENGTH;
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -31,6 +226,10 @@ pub enum Component {
 
 #[derive(Debug, Serialize, Deserialize, Copy, Clone)]
 pub struct ClientCommand {
+    // Increases with every command sent, lets client match server state to its inputs
+    pub sequence: u32,
+    // Newest server tick client had received when it sent this. Only used for stats
+    pub tick_ack: u64,
     pub movement_direction: na::Vector2<i8>,
     // NOTE: I can change f32 to i16/i8. Not sure if it's needed though
     pub orientation: na::UnitQuaternion<f64>,
@@ -41,3 +240,8 @@ pub struct ClientCommand {
     pub pickup: bool,
     pub prop_spawn: Option<u8>,
 }
+
+impl Message for ClientCommand {
+    // Fixed size, about 50 bytes
+    const MAX_SIZE: usize = 256;
+}
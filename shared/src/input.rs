@@ -0,0 +1,213 @@
+// Commands arrive at whatever pace the network delivers them, simulation takes one input per tick.
+// Each command is applied for exactly one tick, in the order they were sent, and is the one acked
+// back to the client. Client prediction relies on that, see client's prediction.rs. Only when the
+// client falls behind is the last command repeated, and only when it gets too far ahead are the
+// oldest ones skipped; the position sent along with the ack corrects the prediction then.
+// One-shot actions are counted instead, so none of them are lost when commands are skipped.
+// They are handed out one per tick
+
+use crate::commands::ClientCommand;
+use std::collections::VecDeque;
+
+// Client has no reason to queue more than that, the rest are dropped
+const MAX_PENDING_ACTIONS: usize = 16;
+// Enough to ride out a bit of jitter, anything more is latency the player would feel
+pub const MAX_QUEUED_COMMANDS: usize = 3;
+
+#[derive(Default)]
+pub struct InputQueue {
+    commands: VecDeque<ClientCommand>,
+    // Applied last, repeated until the next one arrives
+    held: Option<ClientCommand>,
+    jumps: usize,
+    pickups: usize,
+    prop_spawns: VecDeque<u8>,
+}
+
+impl InputQueue {
+    pub fn push(&mut self, command: ClientCommand) {
+        // Jump key is held down, only the press counts as an action
+        let was_jumping = self
+            .commands
+            .back()
+            .or(self.held.as_ref())
+            .map_or(false, |previous| previous.jump);
+        if command.jump && !was_jumping {
+            self.jumps = (self.jumps + 1).min(MAX_PENDING_ACTIONS);
+        }
+        if command.pickup {
+            self.pickups = (self.pickups + 1).min(MAX_PENDING_ACTIONS);
+        }
+        if let Some(prop) = command.prop_spawn {
+            if self.prop_spawns.len() < MAX_PENDING_ACTIONS {
+                self.prop_spawns.push_back(prop);
+            }
+        }
+        self.commands.push_back(command);
+        if self.commands.len() > MAX_QUEUED_COMMANDS {
+            self.commands.pop_front();
+        }
+    }
+    // Input for the next tick, None until the first command arrives
+    pub fn next(&mut self) -> Option<ClientCommand> {
+        if let Some(command) = self.commands.pop_front() {
+            self.held = Some(command);
+        }
+        let mut command = self.held?;
+        command.jump = command.jump || self.jumps > 0;
+        command.pickup = self.pickups > 0;
+        command.prop_spawn = self.prop_spawns.pop_front();
+        self.jumps = self.jumps.saturating_sub(1);
+        self.pickups = self.pickups.saturating_sub(1);
+        Some(command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(sequence: u32) -> ClientCommand {
+        ClientCommand {
+            sequence,
+            tick_ack: 0,
+            movement_direction: na::Vector2::new(0, 127),
+            orientation: na::UnitQuaternion::identity(),
+            fly: false,
+            jump: false,
+            run: false,
+            sit: false,
+            pickup: false,
+            prop_spawn: None,
+        }
+    }
+
+    #[test]
+    fn nothing_before_first_command() {
+        let mut queue = InputQueue::default();
+        assert!(queue.next().is_none());
+    }
+
+    #[test]
+    fn held_state_persists_across_ticks() {
+        let mut queue = InputQueue::default();
+        let mut running = command(1);
+        running.run = true;
+        queue.push(running);
+        for _ in 0..3 {
+            let input = queue.next().unwrap();
+            assert_eq!(input.sequence, 1);
+            assert!(input.run);
+            assert_eq!(input.movement_direction, na::Vector2::new(0, 127));
+        }
+    }
+
+    #[test]
+    fn commands_are_applied_one_per_tick() {
+        let mut queue = InputQueue::default();
+        queue.push(command(1));
+        let mut second = command(2);
+        second.movement_direction = na::Vector2::new(127, 0);
+        queue.push(second);
+        assert_eq!(queue.next().unwrap().sequence, 1);
+        let input = queue.next().unwrap();
+        assert_eq!(input.sequence, 2);
+        assert_eq!(input.movement_direction, na::Vector2::new(127, 0));
+    }
+
+    #[test]
+    fn burst_skips_oldest_commands() {
+        let mut queue = InputQueue::default();
+        for sequence in 1..=10 {
+            queue.push(command(sequence));
+        }
+        let applied: Vec<_> = (0..4).map(|_| queue.next().unwrap().sequence).collect();
+        let first = 10 - MAX_QUEUED_COMMANDS as u32 + 1;
+        assert_eq!(applied, vec![first, first + 1, 10, 10]);
+    }
+
+    #[test]
+    fn burst_keeps_one_shot_actions() {
+        let mut queue = InputQueue::default();
+        let mut pickup = command(1);
+        pickup.pickup = true;
+        let mut spawn = command(2);
+        spawn.prop_spawn = Some(1);
+        queue.push(pickup);
+        queue.push(spawn);
+        queue.push(command(3));
+
+        let input = queue.next().unwrap();
+        assert_eq!(input.sequence, 1);
+        assert!(input.pickup);
+        assert_eq!(input.prop_spawn, Some(1));
+
+        let input = queue.next().unwrap();
+        assert!(!input.pickup);
+        assert_eq!(input.prop_spawn, None);
+    }
+
+    #[test]
+    fn repeated_actions_are_spread_over_ticks() {
+        let mut queue = InputQueue::default();
+        for (sequence, prop) in [0u8, 1, 0].iter().enumerate() {
+            let mut spawn = command(sequence as u32);
+            spawn.prop_spawn = Some(*prop);
+            spawn.pickup = true;
+            queue.push(spawn);
+        }
+        let spawned: Vec<_> = (0..4).map(|_| queue.next().unwrap()).collect();
+        assert_eq!(
+            spawned
+                .iter()
+                .map(|input| input.prop_spawn)
+                .collect::<Vec<_>>(),
+            vec![Some(0), Some(1), Some(0), None]
+        );
+        assert_eq!(
+            spawned.iter().map(|input| input.pickup).collect::<Vec<_>>(),
+            vec![true, true, true, false]
+        );
+    }
+
+    #[test]
+    fn jump_press_between_ticks_is_not_lost() {
+        let mut queue = InputQueue::default();
+        let mut jump = command(1);
+        jump.jump = true;
+        queue.push(jump);
+        queue.push(command(2));
+        assert!(queue.next().unwrap().jump);
+        assert!(!queue.next().unwrap().jump);
+    }
+
+    #[test]
+    fn held_jump_counts_once() {
+        let mut queue = InputQueue::default();
+        for sequence in 0..3 {
+            let mut jump = command(sequence);
+            jump.jump = true;
+            queue.push(jump);
+        }
+        // Pushes the whole press out of the queue
+        for sequence in 3..6 {
+            queue.push(command(sequence));
+        }
+        let jumps: Vec<_> = (0..3).map(|_| queue.next().unwrap().jump).collect();
+        assert_eq!(jumps, vec![true, false, false]);
+    }
+
+    #[test]
+    fn pending_actions_are_capped() {
+        let mut queue = InputQueue::default();
+        for sequence in 0..100 {
+            let mut spawn = command(sequence);
+            spawn.prop_spawn = Some(0);
+            queue.push(spawn);
+        }
+        let spawned = (0..100)
+            .filter(|_| queue.next().unwrap().prop_spawn.is_some())
+            .count();
+        assert_eq!(spawned, MAX_PENDING_ACTIONS);
+    }
+}
@@ -1,9 +1,13 @@
 extern crate nalgebra as na;
 
+pub mod codec;
 pub mod commands;
 pub mod components;
+pub mod input;
+pub mod movement;
 pub mod network;
 pub mod planet;
+pub mod transport;
 
 use rand::{
     distributions::{Distribution, Standard},
@@ -0,0 +1,74 @@
+// Player movement rules. Server applies them to the physics body, client uses them to predict its own character
+
+use crate::commands::ClientCommand;
+
+const WALK_SPEED: f64 = 8.0;
+// Applied for one physics step
+const JUMP_FORCE: f64 = 20000.0;
+
+pub struct Movement {
+    pub rotation: na::UnitQuaternion<f64>,
+    // Velocity player has after the step. None if player can't control it, e.g. when falling
+    pub velocity: Option<na::Vector3<f64>>,
+    pub jump_force: Option<na::Vector3<f64>>,
+}
+
+pub fn movement(
+    command: &ClientCommand,
+    position: &na::Vector3<f64>,
+    planet_radius: f64,
+    on_surface: bool,
+) -> Movement {
+    // Convert from i8 to float. Normalize to avoid cheating
+    let mut movement_direction = na::Vector3::new(
+        command.movement_direction.x as f64,
+        0.0,
+        -command.movement_direction.y as f64,
+    )
+    .try_normalize(0.5)
+    .unwrap_or(na::Vector3::repeat(0.0));
+
+    if command.run {
+        movement_direction *= 2.0;
+    }
+    if command.sit {
+        movement_direction *= 0.2;
+    }
+    if command.jump && command.sit {
+        movement_direction.y = -1.0;
+    }
+    // If we assume that planet origin is zero
+    let q = na::UnitQuaternion::face_towards(position, &na::Vector3::z());
+    let rotation = q * command.orientation;
+
+    let mut movement_direction_transformed = q.transform_vector(&movement_direction.xzy());
+    let altitude = position.norm() - planet_radius;
+    let up = q.transform_vector(&na::Vector3::new(0.0, 0.0, 1.0));
+
+    if command.fly {
+        if command.jump && !command.sit {
+            movement_direction_transformed += up;
+        }
+        Movement {
+            rotation,
+            velocity: Some(movement_direction_transformed * altitude.abs().max(1.0)),
+            jump_force: None,
+        }
+    } else if on_surface {
+        Movement {
+            rotation,
+            velocity: Some(movement_direction_transformed * WALK_SPEED),
+            jump_force: if command.jump {
+                Some(up * JUMP_FORCE)
+            } else {
+                None
+            },
+        }
+    } else {
+        Movement {
+            rotation,
+            velocity: None,
+            jump_force: None,
+        }
+    }
+}
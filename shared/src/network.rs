@@ -1,30 +1,399 @@
-pub fn serialize<T: serde::Serialize + ?Sized>(message: &T) -> Vec<u8> {
-    let len = bincode::serialized_size(message).unwrap() as u32;
-    let mut buf = Vec::new();
-    let l = len.to_le_bytes();
-    buf.extend_from_slice(&l[0..3]);
-    bincode::serialize_into(&mut buf, message).unwrap();
-    buf
+use crate::transport::{Connection, RecvStream};
+use bincode::Options;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures::stream::{BoxStream, StreamExt};
+
+// Length prefix is 24 bits wide, so a single frame can't carry more than this
+pub const MAX_MESSAGE_LENGTH: usize = (1 << 24) - 1;
+
+// Anything that goes over the wire. Peer is not trusted, so every message type has a size cap:
+// frames over it are rejected before anything is allocated for them,
+// and decoding can't read past it, no matter what lengths are inside
+pub trait Message: serde::Serialize + serde::de::DeserializeOwned {
+    const MAX_SIZE: usize;
 }
 
-pub async fn send<T: serde::Serialize>(stream: &mut quinn::SendStream, message: &T) {
-    let data = serialize(message);
-    if data.len() > 2u64.pow(24) as usize {
-        panic!(
-            "Message exceeds maximum length! ({} > {})",
-            data.len(),
-            2u64.pow(24)
-        );
+#[derive(Debug)]
+pub enum NetworkError {
+    // Connection was lost or couldn't be established
+    Connection(quinn::ConnectionError),
+    // Stream was reset or the connection was lost while reading or writing
+    Stream(std::io::Error),
+    Datagram(quinn::SendDatagramError),
+    // Message is bigger than its type allows, see Message::MAX_SIZE
+    Oversize { len: usize, max: usize },
+    Decode(bincode::Error),
+    // Peer finished the stream, there is nothing left to read
+    Closed,
+}
+
+impl std::fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            NetworkError::Connection(e) => write!(f, "connection lost: {}", e),
+            NetworkError::Stream(e) => write!(f, "stream failed: {}", e),
+            NetworkError::Datagram(e) => write!(f, "datagram send failed: {}", e),
+            NetworkError::Oversize { len, max } => {
+                write!(f, "message exceeds maximum length ({} > {})", len, max)
+            }
+            NetworkError::Decode(e) => write!(f, "malformed message: {}", e),
+            NetworkError::Closed => write!(f, "stream closed by peer"),
+        }
+    }
+}
+
+impl std::error::Error for NetworkError {}
+
+impl NetworkError {
+    // Connection broke without the peer asking for it, it may come back.
+    // Peer that's leaving finishes its streams or closes the connection, neither counts
+    pub fn is_lost(&self) -> bool {
+        match self {
+            NetworkError::Connection(e) => !closed_by_peer(e),
+            NetworkError::Stream(e) => !stream_closed_by_peer(e),
+            _ => false,
+        }
+    }
+}
+
+fn closed_by_peer(e: &quinn::ConnectionError) -> bool {
+    matches!(e, quinn::ConnectionError::ApplicationClosed(_))
+}
+
+// Reads and writes on a QUIC connection the peer has closed fail with a stream error, quinn keeps the reason inside
+fn stream_closed_by_peer(e: &std::io::Error) -> bool {
+    let inner = match e.get_ref() {
+        Some(inner) => inner,
+        None => return false,
+    };
+    if let Some(quinn::ReadError::ConnectionClosed(e)) = inner.downcast_ref() {
+        return closed_by_peer(e);
+    }
+    if let Some(quinn::WriteError::ConnectionClosed(e)) = inner.downcast_ref() {
+        return closed_by_peer(e);
+    }
+    false
+}
+
+impl From<quinn::ConnectionError> for NetworkError {
+    fn from(e: quinn::ConnectionError) -> Self {
+        NetworkError::Connection(e)
+    }
+}
+
+impl From<quinn::SendDatagramError> for NetworkError {
+    fn from(e: quinn::SendDatagramError) -> Self {
+        NetworkError::Datagram(e)
+    }
+}
+
+impl From<std::io::Error> for NetworkError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            // Stream ended in the middle of a message
+            std::io::ErrorKind::UnexpectedEof => NetworkError::Closed,
+            _ => NetworkError::Stream(e),
+        }
     }
-    // FIXME: Return result, not unwrap
-    let _ = stream.write_all(&data).await.unwrap();
 }
 
-pub async fn receive<T: serde::de::DeserializeOwned>(stream: &mut quinn::RecvStream) -> Option<T> {
-    let mut l = [0; 4];
-    stream.read_exact(&mut l[0..3]).await.unwrap();
-    let len = u32::from_le_bytes(l) as usize;
+impl From<bincode::Error> for NetworkError {
+    fn from(e: bincode::Error) -> Self {
+        NetworkError::Decode(e)
+    }
+}
+
+fn options(limit: usize) -> impl Options {
+    // Same encoding bincode::serialize uses
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(limit as u64)
+}
+
+fn check_size<T: Message>(len: usize) -> Result<(), NetworkError> {
+    let max = T::MAX_SIZE.min(MAX_MESSAGE_LENGTH);
+    if len > max {
+        return Err(NetworkError::Oversize { len, max });
+    }
+    Ok(())
+}
+
+pub fn serialize<T: Message>(message: &T) -> Result<Vec<u8>, NetworkError> {
+    let len = bincode::serialized_size(message)? as usize;
+    check_size::<T>(len)?;
+    let mut buf = Vec::with_capacity(len + 3);
+    let l = (len as u32).to_le_bytes();
+    buf.extend_from_slice(&l[0..3]);
+    bincode::serialize_into(&mut buf, message)?;
+    Ok(buf)
+}
+
+// Reads length prefix and checks it against the cap, nothing is allocated yet
+pub fn frame_length<T: Message>(prefix: [u8; 3]) -> Result<usize, NetworkError> {
+    let len = u32::from_le_bytes([prefix[0], prefix[1], prefix[2], 0]) as usize;
+    check_size::<T>(len)?;
+    Ok(len)
+}
+
+// Decodes a frame without the length prefix
+pub fn decode<T: Message>(data: &[u8]) -> Result<T, NetworkError> {
+    check_size::<T>(data.len())?;
+    Ok(options(data.len()).deserialize(data)?)
+}
+
+// Decodes only what the frame starts with, whatever follows is ignored
+pub fn decode_prefix<T: serde::de::DeserializeOwned>(data: &[u8]) -> Result<T, NetworkError> {
+    Ok(options(data.len())
+        .allow_trailing_bytes()
+        .deserialize(data)?)
+}
+
+pub async fn send<T: Message, S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    message: &T,
+) -> Result<(), NetworkError> {
+    let data = serialize(message)?;
+    stream.write_all(&data).await?;
+    Ok(())
+}
+
+pub async fn receive<T: Message, S: AsyncRead + Unpin>(stream: &mut S) -> Result<T, NetworkError> {
+    decode(&receive_frame::<T, S>(stream).await?)
+}
+
+// Frame that would be decoded as T, without the length prefix. For messages that are read in parts
+pub async fn receive_frame<T: Message, S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Vec<u8>, NetworkError> {
+    let mut prefix = [0; 3];
+    stream.read_exact(&mut prefix).await?;
+    let len = frame_length::<T>(prefix)?;
     let mut buf = vec![0; len];
-    stream.read_exact(&mut buf).await.unwrap();
-    Some(bincode::deserialize(&buf).unwrap())
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+// Tells peer nothing else is coming on this stream
+pub async fn finish<S: AsyncWrite + Unpin>(stream: &mut S) -> Result<(), NetworkError> {
+    stream.close().await?;
+    Ok(())
+}
+
+// Datagrams are self-delimiting, so unlike stream messages they don't need a length prefix
+pub fn send_datagram<T: Message>(
+    connection: &dyn Connection,
+    message: &T,
+) -> Result<(), NetworkError> {
+    let data = bincode::serialize(message)?;
+    check_size::<T>(data.len())?;
+    connection.send_datagram(data)
+}
+
+pub fn decode_datagram<T: Message>(data: &[u8]) -> Result<T, NetworkError> {
+    decode(data)
+}
+
+// Waits for the next stream opened by peer
+pub async fn accept_uni(
+    streams: &mut BoxStream<'static, Result<RecvStream, NetworkError>>,
+) -> Result<RecvStream, NetworkError> {
+    match streams.next().await {
+        Some(stream) => stream,
+        None => Err(NetworkError::Closed),
+    }
+}
+
+// SHA-256 of a DER encoded certificate as colon separated hex. Server prints it, so clients can pin it
+pub fn fingerprint(certificate: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, certificate)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::*;
+    use rand::{Rng, SeedableRng};
+
+    // Valid command, malformed ones are made from it
+    fn sample_command() -> ClientCommand {
+        ClientCommand {
+            sequence: 7,
+            tick_ack: 0,
+            movement_direction: na::Vector2::new(0, 127),
+            orientation: na::UnitQuaternion::identity(),
+            fly: false,
+            jump: true,
+            run: false,
+            sit: false,
+            pickup: false,
+            prop_spawn: Some(1),
+        }
+    }
+
+    // Frame without the length prefix
+    fn encode<T: Message>(message: &T) -> Vec<u8> {
+        serialize(message).unwrap()[3..].to_vec()
+    }
+
+    // Malformed frames a hostile peer could send, without the length prefix. Decoding has to fail cleanly
+    // on every one of them, without panicking or allocating what the frame asks for
+    fn malformed_frames() -> Vec<Vec<u8>> {
+        let valid_command = encode(&sample_command());
+        let valid_info = encode(&ClientInfo::new("player".to_string()));
+        let mut corpus = vec![
+            vec![],
+            vec![0],
+            vec![0xff; 3],
+            // Truncated
+            valid_command[..valid_command.len() - 1].to_vec(),
+            valid_info[..valid_info.len() / 2].to_vec(),
+            // Trailing garbage
+            [valid_command.clone(), vec![0xff; 4]].concat(),
+        ];
+        // Protocol version, then a build hash claiming to be u64::MAX and 2^40 bytes long
+        corpus.push([&7u32.to_le_bytes()[..], &[0xff; 8]].concat());
+        corpus.push([&7u32.to_le_bytes()[..], &(1u64 << 40).to_le_bytes(), b"abc"].concat());
+        // Name that isn't UTF-8
+        corpus.push(
+            [
+                &7u32.to_le_bytes()[..],
+                &0u64.to_le_bytes(),
+                &2u64.to_le_bytes(),
+                &[0xc3, 0x28],
+            ]
+            .concat(),
+        );
+        // Tick with u64::MAX spawns
+        corpus.push(
+            [
+                &0u32.to_le_bytes()[..],
+                &1u64.to_le_bytes(),
+                &u64::MAX.to_le_bytes(),
+            ]
+            .concat(),
+        );
+        // Enum variant that doesn't exist
+        corpus.push(1000u32.to_le_bytes().to_vec());
+        // Bool that is neither 0 nor 1
+        let mut command = valid_command;
+        let jump = command.len() - 6;
+        command[jump] = 2;
+        corpus.push(command);
+        corpus
+    }
+
+    fn decode_all(data: &[u8]) {
+        // Results don't matter, only that nothing panics or blows up
+        let _ = decode::<ClientInfo>(data);
+        let _ = decode::<ClientCommand>(data);
+        let _ = decode::<HandshakeResponse>(data);
+        let _ = decode::<Snapshot>(data);
+        let _ = decode::<ServerMessage>(data);
+        let _ = decode_datagram::<PositionUpdate>(data);
+    }
+
+    #[test]
+    fn valid_frames_round_trip() {
+        let decoded = decode::<ClientCommand>(&encode(&sample_command())).unwrap();
+        assert_eq!(decoded.sequence, 7);
+        assert_eq!(decoded.prop_spawn, Some(1));
+        let info = decode::<ClientInfo>(&encode(&ClientInfo::new("player".to_string()))).unwrap();
+        assert_eq!(info.name, "player");
+    }
+
+    #[test]
+    fn malformed_frames_are_rejected() {
+        for (i, frame) in malformed_frames().iter().enumerate() {
+            assert!(decode::<ClientInfo>(frame).is_err(), "frame {}", i);
+            assert!(decode::<ClientCommand>(frame).is_err(), "frame {}", i);
+            decode_all(frame);
+        }
+    }
+
+    #[test]
+    fn version_is_read_from_any_layout() {
+        // Older builds didn't have the fields that came after the name
+        let old = [
+            &10u32.to_le_bytes()[..],
+            &3u64.to_le_bytes(),
+            b"abc",
+            &2u64.to_le_bytes(),
+            b"me",
+        ]
+        .concat();
+        assert!(decode::<ClientInfo>(&old).is_err());
+        let version = decode_prefix::<ClientVersion>(&old).unwrap();
+        assert_eq!(version.protocol_version, 10);
+        assert_eq!(version.build_hash, "abc");
+        let current = encode(&ClientInfo::new("player".to_string()));
+        let version = decode_prefix::<ClientVersion>(&current).unwrap();
+        assert_eq!(version.protocol_version, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn frame_length_is_checked_before_reading() {
+        let max = ClientCommand::MAX_SIZE as u32;
+        let prefix = |len: u32| {
+            let bytes = len.to_le_bytes();
+            [bytes[0], bytes[1], bytes[2]]
+        };
+        assert_eq!(
+            frame_length::<ClientCommand>(prefix(max)).unwrap(),
+            max as usize
+        );
+        match frame_length::<ClientCommand>(prefix(max + 1)) {
+            Err(NetworkError::Oversize { len, .. }) => assert_eq!(len, max as usize + 1),
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+        assert!(frame_length::<ClientInfo>([0xff; 3]).is_err());
+        assert!(frame_length::<ServerMessage>([0xff; 3]).is_ok());
+    }
+
+    #[test]
+    fn oversized_messages_are_not_sent() {
+        let info = ClientInfo::new("x".repeat(ClientInfo::MAX_SIZE));
+        assert!(matches!(
+            serialize(&info),
+            Err(NetworkError::Oversize { .. })
+        ));
+    }
+
+    #[test]
+    fn random_frames_are_rejected() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1234);
+        for _ in 0..10000 {
+            let len = rng.gen_range(0, 256);
+            let frame: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            decode_all(&frame);
+        }
+    }
+
+    #[test]
+    fn mutated_frames_are_rejected() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(4321);
+        let valid = [
+            encode(&sample_command()),
+            encode(&ClientInfo::new("player".to_string())),
+            encode(&ServerMessage::Tick(Tick {
+                tick: 1,
+                spawns: vec![(crate::EntityId(1), vec![])],
+                despawns: vec![crate::EntityId(2)],
+                welds: vec![],
+                unwelds: vec![(crate::EntityId(3), crate::EntityId(4))],
+            })),
+        ];
+        for _ in 0..10000 {
+            let mut frame = valid[rng.gen_range(0, valid.len())].clone();
+            for _ in 0..rng.gen_range(1, 4) {
+                let i = rng.gen_range(0, frame.len());
+                frame[i] = rng.gen();
+            }
+            decode_all(&frame);
+        }
+    }
 }
@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum PostType {
     Add(f64),
     Sub(f64),
@@ -25,7 +25,7 @@ impl PostType {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum NoiseType {
     Simplex(i64),
     Fbm {
@@ -125,7 +125,7 @@ impl NoiseType {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum LayerType {
     Noise { noise: NoiseType, frequency: f64 },
     Value(f64),
@@ -150,7 +150,7 @@ impl LayerType {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Layer {
     pub layer_type: LayerType,
     pub mask: Option<Box<Layer>>,
@@ -222,6 +222,22 @@ pub struct PlanetProcGen {
 }
 
 impl PlanetProcGen {
+    pub fn from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let data = std::fs::read(path)?;
+        Ok(Self {
+            layers: serde_json::from_slice(&data)?,
+            file_path: path.to_string(),
+            file_hash: hash_data(&data),
+        })
+    }
+    // Layers that came from somewhere else, e.g. from the server. Not backed by a file, so never reloaded
+    pub fn from_layers(layers: Vec<Layer>) -> Self {
+        Self {
+            layers,
+            file_path: String::new(),
+            file_hash: 0,
+        }
+    }
     // FIXME: depth is oblsolete
     pub fn get(&self, point: na::Point3<f64>, depth: u8) -> f64 {
         let mut result = 0.0;
@@ -242,21 +258,20 @@ impl PlanetProcGen {
         result.0 *= i16::MAX as f64;
         result
     }
-    pub fn try_reload(&mut self) -> bool {
-        use std::collections::hash_map::DefaultHasher;
-        use std::hash::{Hash, Hasher};
-        use std::io::BufRead;
-        let mut hasher = DefaultHasher::new();
-        let file = std::fs::File::open(&self.file_path).unwrap();
-        let mut reader = std::io::BufReader::new(file);
-        reader.fill_buf().unwrap().hash(&mut hasher);
-        let hash = hasher.finish();
-        if self.file_hash != hash {
-            self.layers = load_layers_from_file(&std::path::Path::new(&self.file_path));
-            self.file_hash = hash;
-            return true;
+    // Layers from the file at `path`, None if it still hashes to `known_hash`.
+    // Reads and parses the whole file, so keep it off threads that can't wait
+    pub fn read_layers(path: &str, known_hash: u64) -> Result<Option<(u64, Vec<Layer>)>, String> {
+        let data = std::fs::read(path).map_err(|e| e.to_string())?;
+        let hash = hash_data(&data);
+        if hash == known_hash {
+            return Ok(None);
         }
-        return false;
+        let layers = serde_json::from_slice(&data).map_err(|e| e.to_string())?;
+        Ok(Some((hash, layers)))
+    }
+    pub fn set_layers(&mut self, hash: u64, layers: Vec<Layer>) {
+        self.layers = layers;
+        self.file_hash = hash;
     }
     pub fn tree_density_at(&self, point: na::Point3<f64>) -> f64 {
         let x = self.get(point, 254) / i16::MAX as f64 * 5.0 - 1.5;
@@ -278,6 +293,14 @@ impl Default for PlanetProcGen {
     }
 }
 
+fn hash_data(data: &[u8]) -> u64 {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
 fn load_layers_from_file(path: &std::path::Path) -> Vec<Layer> {
     let file = std::fs::File::open(path).unwrap();
     let reader = std::io::BufReader::new(file);
@@ -0,0 +1,235 @@
+// In-memory transport, both ends live in the same process. Lets tests run a server and its clients
+// without sockets or certificates. Everything is delivered right away, simulated.rs can make it worse
+
+use super::{ChannelReader, Connection, Incoming, NewConnection, RecvStream, SendStream};
+use crate::network::NetworkError;
+use futures::channel::mpsc;
+use futures::future::BoxFuture;
+use futures::io::AsyncWrite;
+use futures::{FutureExt, StreamExt};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+// Same as a typical QUIC path, so updates get split the same way
+const MAX_DATAGRAM_SIZE: usize = 1200;
+
+// Hands out connections to the server returned by `listen`
+#[derive(Clone)]
+pub struct Connector {
+    connections: mpsc::UnboundedSender<NewConnection>,
+}
+
+impl Connector {
+    // Server gets the other end from its Incoming
+    pub fn connect(&self) -> Result<NewConnection, NetworkError> {
+        Ok(self.connect_with_cable()?.0)
+    }
+    // Same, but the connection can be broken later on
+    pub fn connect_with_cable(&self) -> Result<(NewConnection, Cable), NetworkError> {
+        let (client, server, cable) = pair_with_cable();
+        self.connections
+            .unbounded_send(server)
+            .map_err(|_| NetworkError::Closed)?;
+        Ok((client, cable))
+    }
+}
+
+// Unlike Connection::close, nothing ends cleanly once it's cut: streams on both ends fail,
+// the way they do when a real connection is lost
+#[derive(Clone)]
+pub struct Cable {
+    state: State,
+}
+
+impl Cable {
+    pub fn cut(&self) {
+        if let Some(open) = self.state.lock().unwrap().take() {
+            for (_, pipe) in open.pipes {
+                let _ = pipe.unbounded_send(Err(std::io::ErrorKind::ConnectionReset.into()));
+            }
+        }
+    }
+}
+
+pub fn listen() -> (Connector, Incoming) {
+    let (tx, rx) = mpsc::unbounded();
+    let incoming = rx
+        .map(|connection| async move { Ok(connection) }.boxed())
+        .boxed();
+    (Connector { connections: tx }, incoming)
+}
+
+// Both ends of a single connection
+pub fn pair() -> (NewConnection, NewConnection) {
+    let (a, b, _) = pair_with_cable();
+    (a, b)
+}
+
+fn pair_with_cable() -> (NewConnection, NewConnection, Cable) {
+    let (streams_a, uni_streams_a) = mpsc::unbounded();
+    let (streams_b, uni_streams_b) = mpsc::unbounded();
+    let (datagrams_a, incoming_datagrams_a) = mpsc::unbounded();
+    let (datagrams_b, incoming_datagrams_b) = mpsc::unbounded();
+    let state = Arc::new(Mutex::new(Some(Open {
+        streams: [streams_a, streams_b],
+        datagrams: [datagrams_a, datagrams_b],
+        pipes: slab::Slab::new(),
+    })));
+    (
+        end(state.clone(), 0, uni_streams_a, incoming_datagrams_a),
+        end(state.clone(), 1, uni_streams_b, incoming_datagrams_b),
+        Cable { state },
+    )
+}
+
+fn end(
+    state: State,
+    side: usize,
+    uni_streams: mpsc::UnboundedReceiver<RecvStream>,
+    datagrams: mpsc::UnboundedReceiver<Vec<u8>>,
+) -> NewConnection {
+    NewConnection {
+        connection: Arc::new(Loopback { state, side }),
+        uni_streams: uni_streams.map(Ok).boxed(),
+        datagrams: datagrams.map(Ok).boxed(),
+    }
+}
+
+// Shared by both ends, closing either one drops it, so everything on the other end stops as well
+type State = Arc<Mutex<Option<Open>>>;
+
+struct Open {
+    // Indexed by the side that receives
+    streams: [mpsc::UnboundedSender<RecvStream>; 2],
+    datagrams: [mpsc::UnboundedSender<Vec<u8>>; 2],
+    // Write halves of every open stream. Readers see the end of the stream once theirs is removed
+    pipes: slab::Slab<mpsc::UnboundedSender<std::io::Result<Vec<u8>>>>,
+}
+
+struct Loopback {
+    state: State,
+    side: usize,
+}
+
+impl Loopback {
+    // Nothing to wait for, the other end gets the stream right away
+    fn open(&self) -> Result<SendStream, NetworkError> {
+        let mut state = self.state.lock().unwrap();
+        let open = state.as_mut().ok_or(NetworkError::Closed)?;
+        let (tx, rx) = mpsc::unbounded();
+        open.streams[1 - self.side]
+            .unbounded_send(Box::new(ChannelReader::new(rx)))
+            .map_err(|_| NetworkError::Closed)?;
+        Ok(Box::new(Writer {
+            state: self.state.clone(),
+            pipe: Some(open.pipes.insert(tx)),
+        }))
+    }
+}
+
+impl Connection for Loopback {
+    fn open_uni(&self) -> BoxFuture<'static, Result<SendStream, NetworkError>> {
+        let result = self.open();
+        async move { result }.boxed()
+    }
+    fn send_datagram(&self, data: Vec<u8>) -> Result<(), NetworkError> {
+        if data.len() > MAX_DATAGRAM_SIZE {
+            return Err(NetworkError::Oversize {
+                len: data.len(),
+                max: MAX_DATAGRAM_SIZE,
+            });
+        }
+        let state = self.state.lock().unwrap();
+        let open = state.as_ref().ok_or(NetworkError::Closed)?;
+        open.datagrams[1 - self.side]
+            .unbounded_send(data)
+            .map_err(|_| NetworkError::Closed)
+    }
+    fn max_datagram_size(&self) -> Option<usize> {
+        Some(MAX_DATAGRAM_SIZE)
+    }
+    fn close(&self, _reason: &[u8]) {
+        self.state.lock().unwrap().take();
+    }
+    // Everything is delivered right away
+    fn rtt(&self) -> Option<Duration> {
+        Some(Duration::from_secs(0))
+    }
+}
+
+struct Writer {
+    state: State,
+    // None once finished, the slot may already belong to another stream
+    pipe: Option<usize>,
+}
+
+impl Writer {
+    fn finish(&mut self) {
+        if let Some(pipe) = self.pipe.take() {
+            if let Some(open) = self.state.lock().unwrap().as_mut() {
+                open.pipes.remove(pipe);
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Writer {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let state = self.state.lock().unwrap();
+        let sent = state
+            .as_ref()
+            .and_then(|open| open.pipes.get(self.pipe?))
+            .map(|pipe| pipe.unbounded_send(Ok(buf.to_vec())).is_ok())
+            .unwrap_or(false);
+        if sent {
+            Poll::Ready(Ok(buf.len()))
+        } else {
+            Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<std::io::Result<()>> {
+        self.get_mut().finish();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn cut_connection_fails_instead_of_finishing() {
+        futures::executor::block_on(async {
+            let (client, mut server, cable) = pair_with_cable();
+            let mut stream = client.connection.open_uni().await.unwrap();
+            stream.write_all(&[1, 2]).await.unwrap();
+            let mut received = crate::network::accept_uni(&mut server.uni_streams)
+                .await
+                .unwrap();
+            cable.cut();
+            let mut buf = vec![];
+            let e = received.read_to_end(&mut buf).await.unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset);
+            // Whatever was sent before still arrives
+            assert_eq!(buf, [1, 2]);
+            assert!(stream.write_all(&[3]).await.is_err());
+            assert!(client.connection.open_uni().await.is_err());
+        });
+    }
+}
@@ -0,0 +1,311 @@
+// Counts bytes and messages going through a connection. Wraps any other transport, only payload is counted,
+// whatever the transport adds on top (headers, acks, retransmits) is not
+
+use super::{Connection, NewConnection, RecvStream, SendStream};
+use crate::network::NetworkError;
+use futures::future::BoxFuture;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::{FutureExt, StreamExt, TryStreamExt};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+#[derive(Default, Debug)]
+pub struct Traffic {
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    // Every datagram is a message, stream messages are told apart by their length prefix
+    pub messages_in: AtomicU64,
+    pub messages_out: AtomicU64,
+}
+
+impl Traffic {
+    pub fn totals(&self) -> Totals {
+        Totals {
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            messages_in: self.messages_in.load(Ordering::Relaxed),
+            messages_out: self.messages_out.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Counters at one point in time
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct Totals {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub messages_in: u64,
+    pub messages_out: u64,
+}
+
+// Per second, between the last two samples
+#[derive(Debug, Clone)]
+pub struct Rates {
+    pub bytes_in: f64,
+    pub bytes_out: f64,
+    pub messages_in: f64,
+    pub messages_out: f64,
+    last: Totals,
+    sampled_at: Instant,
+}
+
+impl Rates {
+    pub fn new() -> Self {
+        Self {
+            bytes_in: 0.0,
+            bytes_out: 0.0,
+            messages_in: 0.0,
+            messages_out: 0.0,
+            last: Totals::default(),
+            sampled_at: Instant::now(),
+        }
+    }
+    // Since the last sample
+    pub fn elapsed(&self) -> Duration {
+        self.sampled_at.elapsed()
+    }
+    // Averages since the previous call, so calling it once a second gives rates for the last second
+    pub fn sample(&mut self, traffic: &Traffic) {
+        let now = Instant::now();
+        self.update(traffic.totals(), now - self.sampled_at);
+        self.sampled_at = now;
+    }
+    fn update(&mut self, totals: Totals, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64().max(1.0e-3);
+        let rate = |total: u64, last: u64| total.saturating_sub(last) as f64 / seconds;
+        self.bytes_in = rate(totals.bytes_in, self.last.bytes_in);
+        self.bytes_out = rate(totals.bytes_out, self.last.bytes_out);
+        self.messages_in = rate(totals.messages_in, self.last.messages_in);
+        self.messages_out = rate(totals.messages_out, self.last.messages_out);
+        self.last = totals;
+    }
+}
+
+impl Default for Rates {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn wrap(connection: NewConnection, traffic: Arc<Traffic>) -> NewConnection {
+    let streams_traffic = traffic.clone();
+    let datagrams_traffic = traffic.clone();
+    NewConnection {
+        connection: Arc::new(Metered {
+            inner: connection.connection,
+            traffic,
+        }),
+        uni_streams: connection
+            .uni_streams
+            .map_ok(move |stream| {
+                Box::new(Counted::new(stream, streams_traffic.clone())) as RecvStream
+            })
+            .boxed(),
+        datagrams: connection
+            .datagrams
+            .inspect_ok(move |datagram| {
+                datagrams_traffic
+                    .bytes_in
+                    .fetch_add(datagram.len() as u64, Ordering::Relaxed);
+                datagrams_traffic
+                    .messages_in
+                    .fetch_add(1, Ordering::Relaxed);
+            })
+            .boxed(),
+    }
+}
+
+struct Metered {
+    inner: Arc<dyn Connection>,
+    traffic: Arc<Traffic>,
+}
+
+impl Connection for Metered {
+    fn open_uni(&self) -> BoxFuture<'static, Result<SendStream, NetworkError>> {
+        let opening = self.inner.open_uni();
+        let traffic = self.traffic.clone();
+        async move { Ok(Box::new(Counted::new(opening.await?, traffic)) as SendStream) }.boxed()
+    }
+    fn send_datagram(&self, data: Vec<u8>) -> Result<(), NetworkError> {
+        let len = data.len() as u64;
+        self.inner.send_datagram(data)?;
+        self.traffic.bytes_out.fetch_add(len, Ordering::Relaxed);
+        self.traffic.messages_out.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+    fn max_datagram_size(&self) -> Option<usize> {
+        self.inner.max_datagram_size()
+    }
+    fn close(&self, reason: &[u8]) {
+        self.inner.close(reason)
+    }
+    fn rtt(&self) -> Option<Duration> {
+        self.inner.rtt()
+    }
+}
+
+// Reading counts as incoming, writing as outgoing
+struct Counted<S> {
+    inner: S,
+    traffic: Arc<Traffic>,
+    frames: Frames,
+}
+
+impl<S> Counted<S> {
+    fn new(inner: S, traffic: Arc<Traffic>) -> Self {
+        Self {
+            inner,
+            traffic,
+            frames: Frames::default(),
+        }
+    }
+}
+
+// Follows the length prefixes network::serialize puts in front of every stream message
+#[derive(Default)]
+struct Frames {
+    prefix: [u8; 3],
+    prefix_len: usize,
+    // Rest of the current message
+    remaining: usize,
+}
+
+impl Frames {
+    // How many messages start in `data`
+    fn count(&mut self, mut data: &[u8]) -> u64 {
+        let mut started = 0;
+        while !data.is_empty() {
+            if self.remaining > 0 {
+                let len = self.remaining.min(data.len());
+                self.remaining -= len;
+                data = &data[len..];
+                continue;
+            }
+            self.prefix[self.prefix_len] = data[0];
+            self.prefix_len += 1;
+            data = &data[1..];
+            if self.prefix_len == self.prefix.len() {
+                let prefix = self.prefix;
+                self.remaining = u32::from_le_bytes([prefix[0], prefix[1], prefix[2], 0]) as usize;
+                self.prefix_len = 0;
+                started += 1;
+            }
+        }
+        started
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let counted = self.get_mut();
+        let result = Pin::new(&mut counted.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(len)) = result {
+            let traffic = &counted.traffic;
+            traffic.bytes_in.fetch_add(len as u64, Ordering::Relaxed);
+            let messages = counted.frames.count(&buf[..len]);
+            traffic.messages_in.fetch_add(messages, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let counted = self.get_mut();
+        let result = Pin::new(&mut counted.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(len)) = result {
+            let traffic = &counted.traffic;
+            traffic.bytes_out.fetch_add(len as u64, Ordering::Relaxed);
+            let messages = counted.frames.count(&buf[..len]);
+            traffic.messages_out.fetch_add(messages, Ordering::Relaxed);
+        }
+        result
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::loopback;
+
+    #[test]
+    fn both_directions_are_counted() {
+        futures::executor::block_on(async {
+            let (client, server) = loopback::pair();
+            let traffic = Arc::new(Traffic::default());
+            let client = wrap(client, traffic.clone());
+
+            let mut stream = client.connection.open_uni().await.unwrap();
+            crate::network::send(&mut stream, &crate::commands::ClientInfo::new("a".into()))
+                .await
+                .unwrap();
+            let sent = traffic.bytes_out.load(Ordering::Relaxed);
+            assert!(sent > 3);
+            client.connection.send_datagram(vec![0; 10]).unwrap();
+            assert_eq!(traffic.bytes_out.load(Ordering::Relaxed), sent + 10);
+            assert_eq!(traffic.messages_out.load(Ordering::Relaxed), 2);
+
+            let mut datagrams = client.datagrams;
+            server.connection.send_datagram(vec![0; 20]).unwrap();
+            datagrams.next().await.unwrap().unwrap();
+            let mut stream = server.connection.open_uni().await.unwrap();
+            futures::io::AsyncWriteExt::write_all(&mut stream, &[1; 5])
+                .await
+                .unwrap();
+            let mut uni_streams = client.uni_streams;
+            let mut received = crate::network::accept_uni(&mut uni_streams).await.unwrap();
+            let mut buf = [0; 5];
+            futures::io::AsyncReadExt::read_exact(&mut received, &mut buf)
+                .await
+                .unwrap();
+            assert_eq!(traffic.bytes_in.load(Ordering::Relaxed), 25);
+        });
+    }
+
+    #[test]
+    fn messages_are_counted_however_they_are_split() {
+        let mut data =
+            crate::network::serialize(&crate::commands::ClientInfo::new("a".into())).unwrap();
+        data.extend(
+            crate::network::serialize(&crate::commands::ClientInfo::new("".into())).unwrap(),
+        );
+        for split in 0..data.len() {
+            let mut frames = Frames::default();
+            let (first, second) = data.split_at(split);
+            assert_eq!(frames.count(first) + frames.count(second), 2);
+            assert_eq!(frames.remaining, 0);
+        }
+    }
+
+    #[test]
+    fn rates_are_per_second() {
+        let mut rates = Rates::new();
+        let totals = Totals {
+            bytes_in: 3000,
+            messages_out: 10,
+            ..Default::default()
+        };
+        rates.update(totals, Duration::from_secs(2));
+        assert_eq!(rates.bytes_in, 1500.0);
+        assert_eq!(rates.messages_out, 5.0);
+        rates.update(totals, Duration::from_secs(1));
+        assert_eq!(rates.bytes_in, 0.0);
+    }
+}
@@ -0,0 +1,88 @@
+// What the game needs from the network: connections that can open ordered streams and send datagrams.
+// Whether that's QUIC (quic.rs) or in-memory channels (loopback.rs, used by tests) is up to whoever
+// creates the connection, shared::network and everything above it work with either
+
+pub mod loopback;
+pub mod metered;
+pub mod quic;
+pub mod simulated;
+
+use crate::network::NetworkError;
+use futures::channel::mpsc;
+use futures::future::BoxFuture;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::stream::{BoxStream, StreamExt};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+pub type SendStream = Box<dyn AsyncWrite + Send + Unpin>;
+pub type RecvStream = Box<dyn AsyncRead + Send + Unpin>;
+
+pub trait Connection: Send + Sync {
+    // Reliable and ordered. Peer gets the other end from NewConnection::uni_streams
+    fn open_uni(&self) -> BoxFuture<'static, Result<SendStream, NetworkError>>;
+    // May be lost or arrive out of order
+    fn send_datagram(&self, data: Vec<u8>) -> Result<(), NetworkError>;
+    // None if peer doesn't accept datagrams
+    fn max_datagram_size(&self) -> Option<usize>;
+    // Streams and datagrams on both ends stop
+    fn close(&self, reason: &[u8]);
+    // As the transport measures it, None if it doesn't
+    fn rtt(&self) -> Option<Duration>;
+}
+
+pub struct NewConnection {
+    pub connection: Arc<dyn Connection>,
+    pub uni_streams: BoxStream<'static, Result<RecvStream, NetworkError>>,
+    pub datagrams: BoxStream<'static, Result<Vec<u8>, NetworkError>>,
+}
+
+// Connections server accepts. Each one is still being established, so they can be awaited concurrently
+pub type Incoming = BoxStream<'static, BoxFuture<'static, Result<NewConnection, NetworkError>>>;
+
+// Chunks sent over a channel, read back as one continuous stream. Ends once the sender is dropped,
+// an error fails the read instead
+pub(crate) struct ChannelReader {
+    data: mpsc::UnboundedReceiver<std::io::Result<Vec<u8>>>,
+    // Rest of the last chunk that didn't fit into the caller's buffer
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl ChannelReader {
+    pub(crate) fn new(data: mpsc::UnboundedReceiver<std::io::Result<Vec<u8>>>) -> Self {
+        Self {
+            data,
+            buffer: vec![],
+            position: 0,
+        }
+    }
+}
+
+impl AsyncRead for ChannelReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let reader = self.get_mut();
+        while reader.position == reader.buffer.len() {
+            match reader.data.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    reader.buffer = chunk;
+                    reader.position = 0;
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                // Writer has finished or the connection was closed
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let len = buf.len().min(reader.buffer.len() - reader.position);
+        buf[..len].copy_from_slice(&reader.buffer[reader.position..reader.position + len]);
+        reader.position += len;
+        Poll::Ready(Ok(len))
+    }
+}
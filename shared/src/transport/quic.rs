@@ -0,0 +1,51 @@
+// QUIC transport, what both server and client use outside of tests
+
+use super::{Connection, Incoming, NewConnection, RecvStream, SendStream};
+use crate::network::NetworkError;
+use futures::future::BoxFuture;
+use futures::{FutureExt, StreamExt, TryStreamExt};
+use std::sync::Arc;
+use std::time::Duration;
+
+impl Connection for quinn::Connection {
+    fn open_uni(&self) -> BoxFuture<'static, Result<SendStream, NetworkError>> {
+        let opening = quinn::Connection::open_uni(self);
+        async move { Ok(Box::new(opening.await?) as SendStream) }.boxed()
+    }
+    fn send_datagram(&self, data: Vec<u8>) -> Result<(), NetworkError> {
+        Ok(quinn::Connection::send_datagram(self, data.into())?)
+    }
+    fn max_datagram_size(&self) -> Option<usize> {
+        quinn::Connection::max_datagram_size(self)
+    }
+    fn close(&self, reason: &[u8]) {
+        quinn::Connection::close(self, quinn::VarInt::from_u32(0), reason)
+    }
+    fn rtt(&self) -> Option<Duration> {
+        Some(quinn::Connection::rtt(self))
+    }
+}
+
+impl From<quinn::NewConnection> for NewConnection {
+    fn from(connection: quinn::NewConnection) -> Self {
+        Self {
+            connection: Arc::new(connection.connection),
+            uni_streams: connection
+                .uni_streams
+                .map_ok(|stream| Box::new(stream) as RecvStream)
+                .err_into()
+                .boxed(),
+            datagrams: connection
+                .datagrams
+                .map_ok(|datagram| datagram.to_vec())
+                .err_into()
+                .boxed(),
+        }
+    }
+}
+
+pub fn incoming(incoming: quinn::Incoming) -> Incoming {
+    incoming
+        .map(|connecting| async move { Ok(connecting.await?.into()) }.boxed())
+        .boxed()
+}
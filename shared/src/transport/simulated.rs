@@ -0,0 +1,368 @@
+// Bad network on demand. Delays, drops and reorders whatever arrives over a connection, so problems
+// remote players have can be reproduced on localhost or with the loopback transport.
+// Only the receiving direction is affected, each end simulates what it gets from the other one.
+// Decisions come from a seeded rng: same seed and same traffic give the same drops and delays
+//
+// NOTE: Delays are scheduled with tokio timers, so this only works inside a tokio runtime
+// NOTE: Transport underneath never sees the delays, they don't show up in Connection::rtt
+
+use super::{ChannelReader, NewConnection, RecvStream};
+use crate::network::NetworkError;
+use futures::channel::mpsc;
+use futures::io::AsyncReadExt;
+use futures::stream::{BoxStream, StreamExt};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Streams are cut into pieces about the size of a packet, each one is delayed or lost on its own
+const CHUNK_SIZE: usize = 1200;
+// How long a reordered datagram is held back, about 3 ticks at 60 ticks/s
+const REORDER_DELAY: Duration = Duration::from_millis(50);
+
+// Everything is optional in config files, nothing is simulated by default
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Conditions {
+    // One-way, in milliseconds
+    pub latency: u64,
+    // Random extra delay up to that, in milliseconds. Enough of it reorders datagrams on its own
+    pub jitter: u64,
+    // Chance of a datagram being dropped. Streams are reliable, their lost packets arrive a round trip later
+    pub loss: f64,
+    // Chance of a datagram being held back, so the next few overtake it
+    pub reorder: f64,
+    // Bytes per second. Whatever doesn't fit waits in a queue
+    pub bandwidth: Option<u64>,
+    // Datagrams that would wait in the queue for longer than that are dropped, in milliseconds
+    pub queue: u64,
+    pub seed: u64,
+}
+
+impl Default for Conditions {
+    fn default() -> Self {
+        Self {
+            latency: 0,
+            jitter: 0,
+            loss: 0.0,
+            reorder: 0.0,
+            bandwidth: None,
+            // Zero would drop every datagram that has to wait at all
+            queue: 200,
+            seed: 0,
+        }
+    }
+}
+
+impl Conditions {
+    // Chances outside of 0-1 (or NaN) are a mistake in the config, not something to guess around
+    pub fn check(&self) -> Result<(), String> {
+        for (name, chance) in &[("loss", self.loss), ("reorder", self.reorder)] {
+            if !(0.0..=1.0).contains(chance) {
+                return Err(format!(
+                    "Simulated {} has to be between 0 and 1, not {}",
+                    name, chance
+                ));
+            }
+        }
+        Ok(())
+    }
+    pub fn is_active(&self) -> bool {
+        self.latency > 0
+            || self.jitter > 0
+            || self.loss > 0.0
+            || self.reorder > 0.0
+            || self.bandwidth.is_some()
+    }
+}
+
+// Shared by everything that arrives over one connection
+struct Link {
+    conditions: Conditions,
+    rng: StdRng,
+    // When the link is done with everything queued so far, only used with a bandwidth cap
+    free_at: Instant,
+}
+
+impl Link {
+    // When a packet received now reaches the application, None if it's lost
+    fn schedule(&mut self, len: usize, reliable: bool) -> Option<Instant> {
+        let conditions = &self.conditions;
+        let now = Instant::now();
+        let mut departure = now;
+        if let Some(bandwidth) = conditions.bandwidth {
+            departure = self.free_at.max(now);
+            if !reliable && departure - now > Duration::from_millis(conditions.queue) {
+                return None;
+            }
+            self.free_at =
+                departure + Duration::from_secs_f64(len as f64 / bandwidth.max(1) as f64);
+        }
+        let latency = Duration::from_millis(conditions.latency);
+        let jitter = Duration::from_millis(conditions.jitter).mul_f64(self.rng.gen());
+        let mut delay = latency + jitter;
+        if self.rng.gen_bool(na::clamp(conditions.loss, 0.0, 1.0)) {
+            if !reliable {
+                return None;
+            }
+            // Sender notices and sends it again, roughly a round trip later
+            delay += latency * 2;
+        }
+        if !reliable && self.rng.gen_bool(na::clamp(conditions.reorder, 0.0, 1.0)) {
+            delay += REORDER_DELAY;
+        }
+        Some(departure + delay)
+    }
+}
+
+// `seed` is used instead of the one in conditions, so every connection gets different, but repeatable drops
+pub fn wrap(connection: NewConnection, conditions: &Conditions, seed: u64) -> NewConnection {
+    let link = Arc::new(Mutex::new(Link {
+        conditions: conditions.clone(),
+        rng: StdRng::seed_from_u64(seed),
+        free_at: Instant::now(),
+    }));
+    let (datagrams_tx, datagrams) = mpsc::unbounded();
+    tokio::spawn(delay_datagrams(
+        connection.datagrams,
+        link.clone(),
+        datagrams_tx,
+    ));
+    let (streams_tx, uni_streams) = mpsc::unbounded();
+    tokio::spawn(delay_streams(connection.uni_streams, link, streams_tx));
+    NewConnection {
+        connection: connection.connection,
+        uni_streams: uni_streams.boxed(),
+        datagrams: datagrams.boxed(),
+    }
+}
+
+async fn delay_datagrams(
+    mut datagrams: BoxStream<'static, Result<Vec<u8>, NetworkError>>,
+    link: Arc<Mutex<Link>>,
+    delayed: mpsc::UnboundedSender<Result<Vec<u8>, NetworkError>>,
+) {
+    while let Some(datagram) = datagrams.next().await {
+        let datagram = match datagram {
+            Ok(datagram) => datagram,
+            Err(e) => {
+                let _ = delayed.unbounded_send(Err(e));
+                return;
+            }
+        };
+        let arrival = match link.lock().unwrap().schedule(datagram.len(), false) {
+            Some(arrival) => arrival,
+            None => continue,
+        };
+        // Each one waits on its own, so they are free to overtake each other
+        let delayed = delayed.clone();
+        tokio::spawn(async move {
+            tokio::time::delay_until(arrival.into()).await;
+            let _ = delayed.unbounded_send(Ok(datagram));
+        });
+    }
+}
+
+async fn delay_streams(
+    mut streams: BoxStream<'static, Result<RecvStream, NetworkError>>,
+    link: Arc<Mutex<Link>>,
+    delayed: mpsc::UnboundedSender<Result<RecvStream, NetworkError>>,
+) {
+    while let Some(stream) = streams.next().await {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                let _ = delayed.unbounded_send(Err(e));
+                return;
+            }
+        };
+        let (chunks_tx, chunks) = mpsc::unbounded();
+        if delayed
+            .unbounded_send(Ok(Box::new(ChannelReader::new(chunks))))
+            .is_err()
+        {
+            return;
+        }
+        tokio::spawn(delay_stream(stream, link.clone(), chunks_tx));
+    }
+}
+
+// Keeps reading while earlier chunks are still waiting, otherwise every chunk would pay the latency again
+async fn delay_stream(
+    mut stream: RecvStream,
+    link: Arc<Mutex<Link>>,
+    delayed: mpsc::UnboundedSender<std::io::Result<Vec<u8>>>,
+) {
+    let (scheduled_tx, mut scheduled) = mpsc::unbounded::<(Instant, std::io::Result<Vec<u8>>)>();
+    tokio::spawn(async move {
+        while let Some((arrival, chunk)) = scheduled.next().await {
+            tokio::time::delay_until(arrival.into()).await;
+            if delayed.unbounded_send(chunk).is_err() {
+                return;
+            }
+        }
+    });
+    let mut previous = Instant::now();
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let len = match stream.read(&mut buf).await {
+            Ok(0) => return,
+            Ok(len) => len,
+            // Goes after everything that was read before it
+            Err(e) => {
+                let _ = scheduled_tx.unbounded_send((previous, Err(e)));
+                return;
+            }
+        };
+        // Stream is ordered, nothing can overtake what was sent before it
+        let arrival = link
+            .lock()
+            .unwrap()
+            .schedule(len, true)
+            .unwrap_or(previous)
+            .max(previous);
+        previous = arrival;
+        if scheduled_tx
+            .unbounded_send((arrival, Ok(buf[..len].to_vec())))
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::loopback;
+    use futures::io::AsyncWriteExt;
+
+    // Single thread, so nothing gets reordered by accident
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    // Sends all datagrams at once, returns what arrived within `wait`
+    fn received(datagrams: Vec<u8>, conditions: &Conditions, wait: Duration) -> Vec<u8> {
+        runtime().block_on(async {
+            let (client, server) = loopback::pair();
+            let mut server = wrap(server, conditions, conditions.seed);
+            for datagram in datagrams {
+                client.connection.send_datagram(vec![datagram]).unwrap();
+            }
+            let deadline = Instant::now() + wait;
+            let mut received = vec![];
+            while let Ok(Some(datagram)) = tokio::time::timeout(
+                deadline.saturating_duration_since(Instant::now()),
+                server.datagrams.next(),
+            )
+            .await
+            {
+                received.push(datagram.unwrap()[0]);
+            }
+            received
+        })
+    }
+
+    #[test]
+    fn datagrams_are_delayed() {
+        let conditions = Conditions {
+            latency: 100,
+            ..Default::default()
+        };
+        runtime().block_on(async {
+            let (client, server) = loopback::pair();
+            let mut server = wrap(server, &conditions, 0);
+            let sent = Instant::now();
+            client.connection.send_datagram(vec![1]).unwrap();
+            server.datagrams.next().await.unwrap().unwrap();
+            assert!(sent.elapsed() >= Duration::from_millis(100));
+        });
+    }
+
+    #[test]
+    fn losses_repeat_with_the_same_seed() {
+        let conditions = Conditions {
+            loss: 0.5,
+            seed: 42,
+            ..Default::default()
+        };
+        let wait = Duration::from_millis(100);
+        let first = received((0..100).collect(), &conditions, wait);
+        assert!(first.len() > 20 && first.len() < 80, "{}", first.len());
+        assert_eq!(first, received((0..100).collect(), &conditions, wait));
+    }
+
+    #[test]
+    fn reordered_datagrams_arrive_late() {
+        let conditions = Conditions {
+            reorder: 0.5,
+            ..Default::default()
+        };
+        let received = received((0..20).collect(), &conditions, Duration::from_millis(300));
+        let mut sorted = received.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..20).collect::<Vec<_>>());
+        assert_ne!(received, sorted);
+    }
+
+    #[test]
+    fn bandwidth_cap_drops_what_doesnt_fit() {
+        // 10 datagrams a second, queue holds half a second of them
+        let conditions = Conditions {
+            bandwidth: Some(10),
+            queue: 500,
+            ..Default::default()
+        };
+        let received = received((0..20).collect(), &conditions, Duration::from_millis(800));
+        assert_eq!(received, (0..6).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn chances_are_checked() {
+        assert!(Conditions::default().check().is_ok());
+        for chance in &[-0.1, 1.5, f64::NAN, f64::INFINITY] {
+            let loss = Conditions {
+                loss: *chance,
+                ..Default::default()
+            };
+            assert!(loss.check().is_err(), "loss {}", chance);
+            let reorder = Conditions {
+                reorder: *chance,
+                ..Default::default()
+            };
+            assert!(reorder.check().is_err(), "reorder {}", chance);
+        }
+    }
+
+    #[test]
+    fn streams_stay_ordered_and_complete() {
+        let conditions = Conditions {
+            latency: 20,
+            jitter: 20,
+            loss: 0.3,
+            bandwidth: Some(1_000_000),
+            ..Default::default()
+        };
+        runtime().block_on(async {
+            let (client, server) = loopback::pair();
+            let mut server = wrap(server, &conditions, 7);
+            let data: Vec<u8> = (0..50_000).map(|i| i as u8).collect();
+            let mut stream = client.connection.open_uni().await.unwrap();
+            stream.write_all(&data).await.unwrap();
+            crate::network::finish(&mut stream).await.unwrap();
+            let mut stream = crate::network::accept_uni(&mut server.uni_streams)
+                .await
+                .unwrap();
+            let mut received = vec![];
+            stream.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, data);
+        });
+    }
+}